@@ -7,6 +7,10 @@ pub struct BootInfo {
     pub framebuffer: Framebuffer,
     pub memory_map: MemoryMap<'static>,
     pub acpi_table: Option<usize>,
+    pub time: Option<Time>,
+    pub ramdisk: Option<Module>,
+    /// Archive in newc cpio or ustar format to unpack as root filesystem
+    pub initrd: Option<Module>,
 }
 
 #[repr(C)]
@@ -19,5 +23,28 @@ pub struct Framebuffer {
     pub buffer: *mut u8,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+    /// Offset from UTC in minutes, `None` when the firmware reports local time
+    pub time_zone: Option<i16>,
+}
+
+/// File loaded by the bootloader, in LOADER_DATA memory.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Module {
+    /// Physical address
+    pub address: usize,
+    pub len: usize,
+}
+
 unsafe impl Send for Framebuffer {}
 unsafe impl Sync for Framebuffer {}
@@ -5,11 +5,12 @@
 use core::arch::asm;
 use core::panic::PanicInfo;
 
-use bootloader::{Framebuffer, BootInfo};
+use bootloader::{Framebuffer, BootInfo, Module, Time};
 
-use uefi::{Handle, Status, entry};
+use uefi::{CStr16, Handle, Status, cstr16, entry};
 use uefi::table::{SystemTable, Boot};
-use uefi::table::boot::{AllocateType, MemoryType};
+use uefi::table::boot::{AllocateType, BootServices, MemoryType};
+use uefi::proto::media::file::{File, FileAttribute, FileMode, RegularFile};
 use uefi::proto::console::gop::GraphicsOutput;
 use uefi::table::cfg::ACPI2_GUID;
 
@@ -51,9 +52,37 @@ fn panic(_info: &PanicInfo) -> ! {
     loop { instructions::hlt(); }
 }
 
+// Load a file from the root of the ESP into memory kept by the kernel
+fn load_module(bt: &BootServices, handle: Handle, path: &CStr16) -> Option<Module> {
+    let mut fs = bt.get_image_file_system(handle).ok()?;
+    let mut file = fs.open_volume().ok()?.open(path, FileMode::Read, FileAttribute::empty()).ok()?.into_regular_file()?;
+
+    file.set_position(RegularFile::END_OF_FILE).ok()?;
+    let len = file.get_position().ok()? as usize;
+    file.set_position(0).ok()?;
+
+    let address = bt.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, len / 4096 + 1).ok()? as usize;
+    let data = unsafe { core::slice::from_raw_parts_mut(address as *mut u8, len) };
+    let mut read = 0;
+    while read < len {
+        match file.read(&mut data[read..]) {
+            Ok(0) | Err(_) => {
+                unsafe { bt.free_pages(address as u64, len / 4096 + 1).ok()? };
+                return None;
+            },
+            Ok(count) => read += count
+        }
+    }
+
+    Some(Module {
+        address,
+        len
+    })
+}
+
 #[entry]
 #[allow(named_asm_labels)]
-fn main(_handle: Handle, system_table: SystemTable<Boot>) -> Status {
+fn main(handle: Handle, system_table: SystemTable<Boot>) -> Status {
     let fb = {
         let bt = system_table.boot_services();
         let gop_handle = bt.get_handle_for_protocol::<GraphicsOutput>().unwrap();
@@ -71,6 +100,22 @@ fn main(_handle: Handle, system_table: SystemTable<Boot>) -> Status {
     let cfg_tbl = system_table.config_table();
     let acpi_table = cfg_tbl.iter().find(|entry| entry.guid == ACPI2_GUID).map(|entry| entry.address as usize);
 
+    // Get wall-clock time from the firmware as runtime services are unavailable to the kernel
+    let time = system_table.runtime_services().get_time().ok().map(|time| Time {
+        year: time.year(),
+        month: time.month(),
+        day: time.day(),
+        hour: time.hour(),
+        minute: time.minute(),
+        second: time.second(),
+        nanosecond: time.nanosecond(),
+        time_zone: time.time_zone()
+    });
+
+    // Optional disk image for the kernel to use as RAM disk
+    let ramdisk = load_module(system_table.boot_services(), handle, cstr16!("ramdisk.img"));
+    let initrd = load_module(system_table.boot_services(), handle, cstr16!("initrd"));
+
     let kernel = include_bytes_aligned!("../../target/x86_64-unknown-none/debug/ios");
     let elf = ElfFile::new(kernel).unwrap();
     let entry_point = elf.header.pt2.entry_point() as usize;
@@ -153,6 +198,9 @@ fn main(_handle: Handle, system_table: SystemTable<Boot>) -> Status {
         let info = BootInfo {
             framebuffer: fb,
             acpi_table: acpi_table,
+            time: time,
+            ramdisk: ramdisk,
+            initrd: initrd,
             memory_map: memory_map,
         };
 
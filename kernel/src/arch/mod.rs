@@ -1,6 +1,6 @@
 mod x86;
 
-pub use x86::KERNEL_ADDRESS_BASE;
+pub use x86::{KERNEL_ADDRESS_BASE, PAGE_SIZE};
 
 pub mod system;
 
@@ -1,7 +1,11 @@
 use alloc::boxed::Box;
 
+use core::time::Duration;
+
 use bitflags::bitflags;
 
+use crate::scheduler::CpuSet;
+
 use super::PageTable;
 
 bitflags! {
@@ -20,19 +24,35 @@ pub enum MemoryMapError {
 
 pub trait System {
     fn sleep();
+    /// Sleep until the next interrupt unless `pending` reports outstanding work.
+    fn idle(pending: impl Fn() -> bool);
+    /// Give up the CPU to the next thread.
+    fn yield_now();
+    fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R;
     fn request_irq_handler(&self, handler: Box<dyn Fn()>) -> Option<u8>;
     unsafe fn map(&self, from: usize, to: usize, length: usize, flags: MemoryFlags) -> Result<(), MemoryMapError>;
     fn memory_barrier();
     fn new_user_page_table(&self) -> PageTable;
+    /// Physical address a kernel virtual address is mapped to.
+    fn translate(&self, address: usize) -> Option<usize>;
+    fn monotonic_time() -> Duration;
+    fn cpu_id() -> u32;
+    /// Make the given CPUs reconsider which thread to run.
+    fn reschedule(cpus: CpuSet);
 }
 
 pub trait ThreadContext {
     fn new(rip: u64, stack: u64) -> Self;
+    /// Kernel mode thread starting at `rip` with `arg` as first argument.
+    fn new_kernel(rip: u64, stack: u64, arg: u64) -> Self;
     fn running() -> Self;
+    /// Whether the thread continues in user mode when activated.
+    fn is_user(&self) -> bool;
     unsafe fn activate(&self) -> !;
 }
 
 pub trait PageMapper {
     unsafe fn map(&mut self, from: usize, to: usize, length: usize, map_flags: MemoryFlags) -> Result<(), MemoryMapError>;
+    fn translate(&self, address: usize) -> Option<usize>;
     unsafe fn activate(&self);
 }
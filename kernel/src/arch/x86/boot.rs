@@ -1,3 +1,5 @@
+use alloc::sync::Arc;
+
 use acpi::{AcpiTables, PlatformInfo, InterruptModel};
 
 use core::ops::DerefMut;
@@ -9,17 +11,21 @@ use x86_64::VirtAddr;
 use spin::Mutex;
 
 use crate::arch::system::{PageMapper, System, MemoryFlags};
+use crate::block::ramdisk::RamDisk;
 use crate::drivers::i8042::PcKeyboard;
+use crate::drivers::registry::{Device, DeviceClass};
+use crate::drivers::rtc::CmosRtc;
 use crate::drivers::video::fb::FrameBuffer;
+use crate::fs::initrd;
 use crate::runtime::{Runtime, runtime};
+use crate::time::DateTime;
 use crate::{main, main_cpu, ALLOCATOR};
 
 use super::acpi::IdentityMappedAcpiMemory;
 use super::paging::PageTable;
 use super::smp::{boot_cpu, setup_boot_code};
-use super::{gdt, interrupts, lapic, ioapic, pci, X86, CpuData, KERNEL_ADDRESS_BASE, syscall};
+use super::{gdt, interrupts, lapic, ioapic, pci, X86, CpuData, KERNEL_ADDRESS_BASE, PAGE_SIZE, syscall, tsc};
 
-const PAGE_SIZE: usize = 4096;
 
 #[no_mangle]
 extern "C" fn _start(info: &BootInfo) -> ! {
@@ -78,14 +84,31 @@ extern "C" fn _start(info: &BootInfo) -> ! {
 
     unsafe { system.memory.lock().activate(); }
 
-    Runtime::init(system, fb, keyboard);
+    Runtime::init(system, fb, keyboard, CmosRtc::new());
+    runtime().devices.add("kbd0", None, Device::Other(DeviceClass::Input, runtime().keyboard.clone())).unwrap();
+    if let Some(ramdisk) = info.ramdisk {
+        // The image is in LOADER_DATA memory, which is direct mapped and never reused
+        let disk = unsafe { RamDisk::from_static(ramdisk.address + KERNEL_ADDRESS_BASE, ramdisk.len) };
+        runtime().block_devices.add_disk("ram0", Arc::new(disk)).unwrap();
+    }
+    if let Some(initrd) = info.initrd {
+        // Unpacked once the executor runs, the archive stays mapped like the RAM disk image
+        unsafe { initrd::init(initrd.address + KERNEL_ADDRESS_BASE, initrd.len) };
+    }
     let selectors = gdt::init();
     CpuData::new(0, selectors);
 
     interrupts::init();
+    tsc::init();
     lapic::init();
     syscall::init(&CpuData::get().selectors);
 
+    // Prefer the RTC, the firmware time is already outdated after loading the kernel
+    if let Some(time) = runtime().rtc.read_time().or(info.time.as_ref().map(DateTime::from)) {
+        runtime().clock.set_realtime(&time);
+    }
+    runtime().rtc.enable_update_interrupt();
+
     if let Some(acpi_table) = info.acpi_table {
         let acpi_table = unsafe { AcpiTables::from_rsdp(IdentityMappedAcpiMemory::default(), acpi_table as usize).unwrap() };
         let platform_info = PlatformInfo::new(&acpi_table).unwrap();
@@ -115,6 +138,7 @@ extern "C" fn _start(info: &BootInfo) -> ! {
 pub fn start_cpu(cpu_id: u32) -> ! {
     let selectors = gdt::init();
     CpuData::new(cpu_id, selectors);
+    runtime().scheduler.add_idle_thread();
 
     interrupts::init();
     lapic::init();
@@ -1,4 +1,5 @@
 use alloc::boxed::Box;
+use alloc::vec;
 
 use x86_64::VirtAddr;
 use x86_64::instructions::segmentation::Segment;
@@ -19,10 +20,11 @@ pub fn init() -> Selectors {
     let tss = {
         let mut tss = TaskStateSegment::new();
         tss.privilege_stack_table[0] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            const STACK_SIZE: usize = 4096 * 4;
+            // Every CPU needs its own stack to handle interrupts from userspace
+            let stack = vec![0u8; STACK_SIZE].leak();
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            let stack_start = VirtAddr::from_ptr(stack.as_ptr());
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
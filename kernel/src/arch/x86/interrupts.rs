@@ -3,15 +3,19 @@ use spin::Once;
 use x86_64::{set_general_handler, VirtAddr};
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
+use crate::runtime::runtime;
+
 use super::lapic::general_interrupt_handler;
-use super::{lapic, ioapic};
+use super::{lapic, ioapic, tsc};
 
 pub const IOAPIC_INTERRUPT_OFFSET: usize = 32;
 pub const KEYBOARD_INTERRUPT_INDEX: usize = IOAPIC_INTERRUPT_OFFSET + 1;
+pub const RTC_INTERRUPT_INDEX: usize = IOAPIC_INTERRUPT_OFFSET + 8;
 
 pub const SPURIOUS_INTERRUPT_INDEX: usize = 240;
 pub const TIMER_INTERRUPT_INDEX: usize = 241;
 pub const ERROR_INTERRUPT_INDEX: usize = 242;
+pub const YIELD_INTERRUPT_INDEX: usize = 243;
 
 static IDT: Once<InterruptDescriptorTable> = Once::new();
 
@@ -28,8 +32,10 @@ pub fn init() {
         idt[SPURIOUS_INTERRUPT_INDEX].set_handler_fn(lapic::spurious_interrupt_handler);
         unsafe { idt[TIMER_INTERRUPT_INDEX].set_handler_addr(VirtAddr::new(lapic::timer_interrupt_handler as u64)) };
         idt[ERROR_INTERRUPT_INDEX].set_handler_fn(lapic::error_interrupt_handler);
+        unsafe { idt[YIELD_INTERRUPT_INDEX].set_handler_addr(VirtAddr::new(lapic::yield_interrupt_handler as u64)) };
 
         idt[KEYBOARD_INTERRUPT_INDEX].set_handler_fn(ioapic::keyboard_interrupt_handler);
+        idt[RTC_INTERRUPT_INDEX].set_handler_fn(ioapic::rtc_interrupt_handler);
 
         set_general_handler!(&mut idt, general_interrupt_handler, 64..240);
         idt
@@ -38,6 +44,13 @@ pub fn init() {
     x86_64::instructions::interrupts::enable();
 }
 
+/// Run an interrupt handler and charge its time to interrupt handling of this CPU.
+pub fn accounted(handler: impl FnOnce()) {
+    let start = tsc::monotonic_time();
+    handler();
+    runtime().scheduler.account_irq(tsc::monotonic_time() - start);
+}
+
 extern "x86-interrupt" fn general_protection_handler(_: InterruptStackFrame, error_code: u64)  {
     panic!("GP Fault {}", error_code);
 }
@@ -4,7 +4,7 @@ use x86_64::structures::idt::InterruptStackFrame;
 
 use crate::runtime::runtime;
 
-use super::interrupts::{IOAPIC_INTERRUPT_OFFSET, KEYBOARD_INTERRUPT_INDEX};
+use super::interrupts::{self, IOAPIC_INTERRUPT_OFFSET, KEYBOARD_INTERRUPT_INDEX, RTC_INTERRUPT_INDEX};
 use super::lapic::local_apic;
 
 pub fn init(base_addr: u64, id: u8) {
@@ -13,11 +13,24 @@ pub fn init(base_addr: u64, id: u8) {
         ioapic.set_id(id);
         ioapic.init(IOAPIC_INTERRUPT_OFFSET as u8);
         ioapic.enable_irq((KEYBOARD_INTERRUPT_INDEX - IOAPIC_INTERRUPT_OFFSET) as u8);
+        ioapic.enable_irq((RTC_INTERRUPT_INDEX - IOAPIC_INTERRUPT_OFFSET) as u8);
     };
 }
 
 pub extern "x86-interrupt" fn keyboard_interrupt_handler(_: InterruptStackFrame) {
-    runtime().keyboard.read_scancode();
+    interrupts::accounted(|| runtime().keyboard.read_scancode());
+
+    unsafe {
+        local_apic().end_of_interrupt();
+    }
+}
+
+pub extern "x86-interrupt" fn rtc_interrupt_handler(_: InterruptStackFrame) {
+    interrupts::accounted(|| {
+        if let Some(time) = runtime().rtc.handle_interrupt() {
+            runtime().clock.set_realtime(&time);
+        }
+    });
 
     unsafe {
         local_apic().end_of_interrupt();
@@ -1,20 +1,38 @@
 use core::arch::asm;
+use core::arch::x86_64::__cpuid;
 use core::fmt::Write;
 use core::mem;
+use core::time::Duration;
 
 use alloc::boxed::Box;
-use spin::Once;
+use alloc::collections::BTreeMap;
+use spin::{Once, RwLock};
 
-use x2apic::lapic::{LocalApic, LocalApicBuilder, xapic_base};
+use x2apic::lapic::{LocalApic, LocalApicBuilder, TimerDivide, TimerMode, xapic_base};
 
+use x86_64::registers::model_specific::Msr;
 use x86_64::structures::idt::InterruptStackFrame;
 
+use crate::arch::system::ThreadContext;
 use crate::runtime::runtime;
+use crate::scheduler::{CpuSet, QUANTUM};
 
-use super::{interrupts, CpuData};
+use super::{interrupts, tsc, CpuData};
 use super::threads::{Context, ThreadState};
 
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+const CPUID_FEATURE_TSC_DEADLINE: u32 = 1 << 24;
+
 static LOCAL_APIC: Once<LocalApic> = Once::new();
+static TIMER: Once<DeadlineTimer> = Once::new();
+// Local APIC of each CPU, to send it interrupts
+static APIC_IDS: RwLock<BTreeMap<u32, u32>> = RwLock::new(BTreeMap::new());
+
+enum DeadlineTimer {
+    TscDeadline,
+    // Frequency of the timer after dividing
+    OneShot(u64)
+}
 
 pub struct Interrupts {
     pub handlers: [Option<Box<dyn Fn()>>; 240 - 64]
@@ -36,11 +54,88 @@ pub fn init() {
 
     unsafe {
         lapic.enable();
-        lapic.set_timer_mode(x2apic::lapic::TimerMode::Periodic);
-        lapic.set_timer_divide(x2apic::lapic::TimerDivide::Div128);
-        //lapic.disable_timer();
+        APIC_IDS.write().insert(CpuData::get().id, lapic.id());
+    }
+
+    let timer = TIMER.call_once(|| {
+        let features = unsafe { __cpuid(1) };
+        if features.ecx & CPUID_FEATURE_TSC_DEADLINE != 0 {
+            DeadlineTimer::TscDeadline
+        } else {
+            DeadlineTimer::OneShot(calibrate_timer())
+        }
+    });
+
+    unsafe {
+        match timer {
+            DeadlineTimer::TscDeadline => lapic.set_timer_mode(TimerMode::TscDeadline),
+            DeadlineTimer::OneShot(_) => {
+                lapic.set_timer_mode(TimerMode::OneShot);
+                lapic.set_timer_divide(TimerDivide::Div16);
+            }
+        }
         lapic.enable_timer();
     }
+    set_deadline(None);
+}
+
+// Count timer ticks during 10ms of TSC time
+fn calibrate_timer() -> u64 {
+    let lapic = local_apic();
+    unsafe {
+        lapic.set_timer_mode(TimerMode::OneShot);
+        lapic.set_timer_divide(TimerDivide::Div16);
+        lapic.set_timer_initial(u32::MAX);
+
+        let end = tsc::monotonic_time() + Duration::from_millis(10);
+        while tsc::monotonic_time() < end {
+            core::hint::spin_loop();
+        }
+
+        let ticks = u32::MAX - lapic.timer_current();
+        lapic.set_timer_initial(0);
+        ticks as u64 * 100
+    }
+}
+
+/// Fire the timer interrupt at the given monotonic time, or never on `None`.
+pub fn set_deadline(deadline: Option<Duration>) {
+    match (TIMER.wait(), deadline) {
+        (DeadlineTimer::TscDeadline, deadline) => unsafe {
+            // Writing zero disarms the timer
+            Msr::new(IA32_TSC_DEADLINE).write(deadline.map_or(0, tsc::deadline_ticks));
+        },
+        (DeadlineTimer::OneShot(frequency), Some(deadline)) => {
+            let remaining = deadline.saturating_sub(tsc::monotonic_time());
+            let count = (remaining.as_nanos() * *frequency as u128 / 1_000_000_000).clamp(1, u32::MAX as u128);
+            unsafe { local_apic().set_timer_initial(count as u32) };
+        },
+        (DeadlineTimer::OneShot(_), None) => unsafe {
+            local_apic().set_timer_initial(0);
+        }
+    }
+}
+
+/// Program the timer for the earliest of the scheduler quantum and pending async timers.
+/// Async tasks only run on the bootstrap processor, so only its timer handles those.
+pub fn arm_timer() {
+    let quantum = runtime().scheduler.needs_preemption().then(|| tsc::monotonic_time() + QUANTUM);
+    let timers = unsafe { local_apic().is_bsp() }.then(|| runtime().timers.next_deadline()).flatten();
+    set_deadline([quantum, timers].into_iter().flatten().min());
+}
+
+/// Interrupt the CPUs with the timer vector to let them pick a new thread.
+pub fn reschedule(cpus: CpuSet) {
+    let current = CpuData::get().id;
+    let apic_ids = APIC_IDS.read();
+    for cpu in cpus.iter() {
+        if cpu == current {
+            // Expire the own timer instead of sending an interrupt to ourselves
+            set_deadline(Some(tsc::monotonic_time()));
+        } else if let Some(apic_id) = apic_ids.get(&cpu) {
+            unsafe { local_apic().send_ipi(interrupts::TIMER_INTERRUPT_INDEX as u8, *apic_id) };
+        }
+    }
 }
 
 #[allow(mutable_transmutes)]
@@ -80,22 +175,55 @@ pub unsafe extern fn timer_interrupt_handler() -> ! {
     "#, sym timer_interrupt, options(noreturn));
 }
 
+#[naked]
+pub unsafe extern fn yield_interrupt_handler() -> ! {
+    asm!(r#"
+        push r15
+        push r14
+        push r13
+        push r12
+        push r11
+        push r10
+        push r9
+        push r8
+        push rdi
+        push rsi
+        push rdx
+        push rcx
+        push rbx
+        push rax
+        push rbp
+        mov rdi, rsp
+        sub rsp, 0x800
+        jmp {}
+    "#, sym yield_interrupt, options(noreturn));
+}
+
 extern "C" fn timer_interrupt(ctx: &Context) {
     unsafe {
-        if !local_apic().is_bsp() {
-            ctx.restore();
+        if local_apic().is_bsp() {
+            interrupts::accounted(|| runtime().timers.expire(tsc::monotonic_time()));
         }
+        local_apic().end_of_interrupt();
     }
 
+    switch_thread(ctx);
+}
+
+extern "C" fn yield_interrupt(ctx: &Context) {
+    switch_thread(ctx);
+}
+
+fn switch_thread(ctx: &Context) -> ! {
     {
         let mut thread = runtime().scheduler.get_current_context();
         thread.state = ThreadState::Paused(ctx.clone());
     }
 
-    unsafe {
-        local_apic().end_of_interrupt();
-        runtime().scheduler.run_next();
-    }
+    let state = runtime().scheduler.schedule();
+    arm_timer();
+
+    unsafe { state.activate() }
 }
 
 pub extern "x86-interrupt" fn error_interrupt_handler(_: InterruptStackFrame) {
@@ -103,6 +231,8 @@ pub extern "x86-interrupt" fn error_interrupt_handler(_: InterruptStackFrame) {
 }
 
 pub fn general_interrupt_handler(_stack_frame: InterruptStackFrame, index: u8, _error_code: Option<u64>) {
-    CpuData::get().interrupts.handlers[index as usize - 64].as_ref().map(|handler| handler());
+    interrupts::accounted(|| {
+        CpuData::get().interrupts.handlers[index as usize - 64].as_ref().map(|handler| handler());
+    });
     unsafe { local_apic().end_of_interrupt() };
 }
@@ -1,10 +1,14 @@
 use alloc::boxed::Box;
 
+use core::time::Duration;
+
 use spin::Mutex;
 
 use x86_64::{instructions, registers::model_specific::KernelGsBase, VirtAddr};
 
 use crate::arch::system::PageMapper;
+use crate::runtime::runtime;
+use crate::scheduler::{CpuSet, Mode};
 
 use self::gdt::Selectors;
 use self::lapic::Interrupts;
@@ -23,8 +27,10 @@ pub mod gdt;
 pub mod smp;
 pub mod syscall;
 pub mod threads;
+pub mod tsc;
 
 pub const KERNEL_ADDRESS_BASE: usize = 0xffff800000000000;
+pub const PAGE_SIZE: usize = 4096;
 
 pub struct CpuData {
     pub id: u32,
@@ -62,6 +68,36 @@ impl System for X86 {
         instructions::hlt();
     }
 
+    fn idle(pending: impl Fn() -> bool) {
+        // Interrupts stay disabled until halting, so no wakeup is lost in between
+        instructions::interrupts::disable();
+        if pending() {
+            instructions::interrupts::enable();
+            return;
+        }
+
+        if runtime().scheduler.idle(&pending) {
+            Self::yield_now();
+            instructions::interrupts::enable();
+        } else if !pending() {
+            lapic::arm_timer();
+            runtime().scheduler.set_mode(Mode::Idle);
+            instructions::interrupts::enable_and_hlt();
+            runtime().scheduler.set_mode(Mode::Kernel);
+        } else {
+            instructions::interrupts::enable();
+        }
+    }
+
+    fn yield_now() {
+        // Resumes here with the original interrupt flag once scheduled again
+        unsafe { core::arch::asm!("int {}", const interrupts::YIELD_INTERRUPT_INDEX) };
+    }
+
+    fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R {
+        instructions::interrupts::without_interrupts(f)
+    }
+
     fn request_irq_handler(&self, handler: Box<dyn Fn()>) -> Option<u8> {
         let empty = CpuData::get().interrupts.handlers.iter_mut().enumerate().find(|(_, h)| h.is_none());
         empty.map(|(i, h)| {
@@ -88,4 +124,20 @@ impl System for X86 {
     fn new_user_page_table(&self) -> super::PageTable {
         unsafe { self.memory.lock().clone() }
     }
+
+    fn translate(&self, address: usize) -> Option<usize> {
+        self.memory.lock().translate(address)
+    }
+
+    fn monotonic_time() -> Duration {
+        tsc::monotonic_time()
+    }
+
+    fn cpu_id() -> u32 {
+        CpuData::get().id
+    }
+
+    fn reschedule(cpus: CpuSet) {
+        lapic::reschedule(cpus);
+    }
 }
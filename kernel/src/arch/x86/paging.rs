@@ -2,7 +2,7 @@ use alloc::alloc;
 
 use core::alloc::Layout;
 
-use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable as NativePageTable, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable as NativePageTable, PageTableFlags, PhysFrame, Size4KiB, Translate};
 use x86_64::{PhysAddr, VirtAddr};
 
 use crate::arch::system::{MemoryMapError, MemoryFlags, PageMapper};
@@ -91,6 +91,10 @@ impl PageMapper for PageTable {
         Ok(())
     }
 
+    fn translate(&self, address: usize) -> Option<usize> {
+        self.mapper.translate_addr(VirtAddr::try_new(address as u64).ok()?).map(|address| address.as_u64() as usize)
+    }
+
     #[inline(always)]
     unsafe fn activate(&self) {
         x86_64::registers::control::Cr3::write(
@@ -8,6 +8,7 @@ use spin::{Mutex, Lazy};
 
 use x86_64::instructions::port::Port;
 
+use crate::block::devices::disk_name;
 use crate::drivers::block::virtio_blk::VirtioBlk;
 use crate::drivers::pci::PciDevice;
 use crate::runtime::runtime;
@@ -86,6 +87,7 @@ pub fn init() {
 	writeln!(runtime().console.lock(), "Scanning PCI Busses 0 to {}", PCI_MAX_BUS_NUMBER - 1).unwrap();
 
 	let pci_config = PciConfigRegion::new();
+	let mut disks = 0;
 	for bus in 0..PCI_MAX_BUS_NUMBER {
 		for device in 0..PCI_MAX_DEVICE_NUMBER {
 			let pci_address = PciAddress::new(0, bus, device, 0);
@@ -102,7 +104,8 @@ pub fn init() {
 				match (vendor_id, device_id) {
 					(0x1af4, 0x1001) => {
 						let driver = VirtioBlk::new(Arc::new(device)).unwrap();
-						runtime().register(Arc::new(driver));
+						runtime().block_devices.add_disk(&disk_name("vd", disks), Arc::new(driver)).unwrap();
+						disks += 1;
 					},
 					_ => {}
 				}
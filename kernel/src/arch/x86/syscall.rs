@@ -1,5 +1,6 @@
 use core::arch::asm;
 use core::fmt::Write;
+use core::mem;
 use core::slice;
 use core::str;
 
@@ -7,10 +8,28 @@ use x86_64::VirtAddr;
 use x86_64::registers::model_specific::{Efer, EferFlags, Star, LStar, SFMask};
 use x86_64::registers::rflags::RFlags;
 
+use crate::process::ThreadId;
 use crate::runtime::runtime;
+use crate::scheduler::{CpuSet, Mode, Policy, RawCpuStats, RawThreadStats};
+use crate::time::Timespec;
 
+use super::KERNEL_ADDRESS_BASE;
 use super::gdt::Selectors;
 
+const SYSCALL_WRITE: u64 = 1;
+const SYSCALL_CLOCK_GETTIME: u64 = 2;
+const SYSCALL_SCHED_SETPOLICY: u64 = 3;
+const SYSCALL_SCHED_SETNICE: u64 = 4;
+const SYSCALL_SCHED_SETAFFINITY: u64 = 5;
+const SYSCALL_CPU_STATS: u64 = 6;
+const SYSCALL_THREAD_STATS: u64 = 7;
+
+const SCHED_FAIR: u64 = 0;
+const SCHED_REALTIME: u64 = 1;
+const SCHED_IDLE: u64 = 2;
+
+const SYSCALL_ERROR: u64 = u64::MAX;
+
 pub fn init(selectors: &Selectors) {
     unsafe {
         Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
@@ -38,20 +57,94 @@ unsafe extern fn _handle_syscall() {
         pop r8
         pop rdx
         pop rcx
-        pop rax
+        add rsp, 8
         sysretq
     "#, sym handle_syscall, options(noreturn));
 }
 
-extern "C" fn handle_syscall(instr: u64, arg1: u64, arg2: u64) {
+extern "C" fn handle_syscall(instr: u64, arg1: u64, arg2: u64) -> u64 {
+    runtime().scheduler.set_mode(Mode::Kernel);
+    let result = syscall(instr, arg1, arg2);
+    runtime().scheduler.set_mode(Mode::User);
+    result
+}
+
+fn syscall(instr: u64, arg1: u64, arg2: u64) -> u64 {
     match instr {
-        1 => {
+        SYSCALL_WRITE => {
             let buffer = unsafe { slice::from_raw_parts(arg1 as *const u8, arg2 as usize) };
             let string = str::from_utf8(buffer).unwrap();
             runtime().console.lock().write_str(string).unwrap();
+            arg2
+        },
+        SYSCALL_CLOCK_GETTIME => {
+            // The whole structure has to be in user memory
+            let end = arg2.checked_add(mem::size_of::<Timespec>() as u64);
+            if arg2 == 0 || !matches!(end, Some(end) if end as usize <= KERNEL_ADDRESS_BASE) {
+                return SYSCALL_ERROR;
+            }
+
+            match runtime().clock.get(arg1) {
+                Some(time) => {
+                    unsafe { (arg2 as *mut Timespec).write_unaligned(time.into()) };
+                    0
+                },
+                None => SYSCALL_ERROR
+            }
+        },
+        SYSCALL_SCHED_SETPOLICY => {
+            // Policy in the lowest byte followed by the real-time priority
+            let policy = match (arg2 & 0xFF, u8::try_from(arg2 >> 8)) {
+                (SCHED_FAIR, _) => Policy::Fair,
+                (SCHED_REALTIME, Ok(priority)) => Policy::RealTime(priority),
+                (SCHED_IDLE, _) => Policy::Idle,
+                _ => return SYSCALL_ERROR
+            };
+            status(runtime().scheduler.set_policy(thread_id(arg1), policy))
+        },
+        SYSCALL_SCHED_SETNICE => {
+            match i8::try_from(arg2 as i64) {
+                Ok(nice) => status(runtime().scheduler.set_nice(thread_id(arg1), nice)),
+                Err(_) => SYSCALL_ERROR
+            }
+        },
+        SYSCALL_SCHED_SETAFFINITY => status(runtime().scheduler.set_affinity(thread_id(arg1), CpuSet::from_bits(arg2))),
+        SYSCALL_CPU_STATS => {
+            let snapshot = runtime().scheduler.snapshot();
+            copy_to_user(arg1, arg2, snapshot.cpus.iter().map(RawCpuStats::from))
+        },
+        SYSCALL_THREAD_STATS => {
+            let snapshot = runtime().scheduler.snapshot();
+            copy_to_user(arg1, arg2, snapshot.threads.iter().map(RawThreadStats::from))
         },
         _ => {
             writeln!(runtime().console.lock(), "HELP!! {}", instr).unwrap();
+            SYSCALL_ERROR
         }
     }
 }
+
+// Zero refers to the calling thread
+fn thread_id(id: u64) -> Option<ThreadId> {
+    (id != 0).then_some(ThreadId(id))
+}
+
+fn status(result: Result<(), &'static str>) -> u64 {
+    result.map_or(SYSCALL_ERROR, |_| 0)
+}
+
+// Fill a user array with at most `len` items, returns the number of items written
+fn copy_to_user<T>(ptr: u64, len: u64, items: impl Iterator<Item = T>) -> u64 {
+    let size = len.saturating_mul(mem::size_of::<T>() as u64);
+    if ptr == 0 || ptr.saturating_add(size) as usize >= KERNEL_ADDRESS_BASE {
+        return SYSCALL_ERROR;
+    }
+
+    let ptr = ptr as *mut T;
+    let mut count = 0;
+    for item in items.take(len as usize) {
+        unsafe { ptr.add(count).write_unaligned(item) };
+        count += 1;
+    }
+    count as u64
+}
@@ -34,6 +34,34 @@ pub struct Context {
 }
 
 impl Context {
+    pub fn kernel(ip: u64, sp: u64, arg: u64) -> Self {
+        let selectors = &CpuData::get().selectors;
+        Self {
+            rbp: 0,
+            rax: 0,
+            rbx: 0,
+            rcx: 0,
+            rdx: 0,
+            rsi: 0,
+            rdi: arg,
+            r8: 0,
+            r9: 0,
+            r10: 0,
+            r11: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+            stack_frame: InterruptStackFrameValue {
+                instruction_pointer: VirtAddr::new(ip),
+                stack_pointer: VirtAddr::new(sp),
+                code_segment: selectors.code.0 as u64,
+                stack_segment: selectors.data.0 as u64,
+                cpu_flags: STACK_FRAME_INTERRUPT_FLAG
+            }
+        }
+    }
+
     #[inline(always)]
     pub unsafe fn restore(&self) -> ! {
         asm!(r#"
@@ -70,10 +98,22 @@ impl ThreadContext for ThreadState {
         Self::Starting(ip, sp)
     }
 
+    fn new_kernel(ip: u64, sp: u64, arg: u64) -> Self {
+        Self::Paused(Context::kernel(ip, sp, arg))
+    }
+
     fn running() -> Self {
         Self::Running
     }
 
+    fn is_user(&self) -> bool {
+        match self {
+            ThreadState::Running => false,
+            ThreadState::Paused(ctx) => ctx.stack_frame.code_segment & 0x3 == 0x3,
+            ThreadState::Starting(_, _) => true
+        }
+    }
+
     unsafe fn activate(&self) -> ! {
         match self {
             ThreadState::Running => panic!("Thread is already running"),
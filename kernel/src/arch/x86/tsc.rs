@@ -0,0 +1,89 @@
+use core::arch::x86_64::_rdtsc;
+use core::time::Duration;
+
+use spin::Once;
+
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const PIT_FREQUENCY: u64 = 1193182;
+const PIT_CHANNEL2_DATA_PORT: u16 = 0x42;
+const PIT_COMMAND_PORT: u16 = 0x43;
+const PIT_GATE_PORT: u16 = 0x61;
+
+// Calibrate over 10ms
+const CALIBRATE_HZ: u64 = 100;
+
+struct Tsc {
+    frequency: u64,
+    start: u64
+}
+
+static TSC: Once<Tsc> = Once::new();
+
+pub fn init() {
+    TSC.call_once(|| {
+        let frequency = interrupts::without_interrupts(calibrate);
+        Tsc {
+            frequency,
+            start: read()
+        }
+    });
+}
+
+#[inline(always)]
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Frequency of the TSC in Hz
+pub fn frequency() -> u64 {
+    TSC.wait().frequency
+}
+
+pub fn monotonic_time() -> Duration {
+    match TSC.get() {
+        Some(tsc) => ticks_to_duration(read().saturating_sub(tsc.start)),
+        None => Duration::ZERO
+    }
+}
+
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos((ticks as u128 * 1_000_000_000 / frequency() as u128) as u64)
+}
+
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() * frequency() as u128 / 1_000_000_000) as u64
+}
+
+/// TSC value at which the monotonic clock reaches `deadline`
+pub fn deadline_ticks(deadline: Duration) -> u64 {
+    TSC.wait().start + duration_to_ticks(deadline)
+}
+
+// Measure TSC ticks during a single countdown of PIT channel 2
+fn calibrate() -> u64 {
+    let mut gate = Port::<u8>::new(PIT_GATE_PORT);
+    let mut command = Port::<u8>::new(PIT_COMMAND_PORT);
+    let mut data = Port::<u8>::new(PIT_CHANNEL2_DATA_PORT);
+
+    let latch = PIT_FREQUENCY / CALIBRATE_HZ;
+    unsafe {
+        // Enable gate of channel 2 and disable the speaker
+        let value = gate.read();
+        gate.write((value & !0x02) | 0x01);
+
+        // Channel 2, lobyte/hibyte, mode 0
+        command.write(0xB0);
+        data.write((latch & 0xFF) as u8);
+        data.write((latch >> 8) as u8);
+
+        let start = read();
+        while gate.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        let end = read();
+
+        (end - start) * CALIBRATE_HZ
+    }
+}
@@ -0,0 +1,353 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use core::fmt::Write;
+use core::time::Duration;
+
+use async_trait::async_trait;
+
+use spin::Mutex;
+
+use crate::runtime::{Resource, runtime};
+use crate::tasks::mutex::AsyncMutex;
+use crate::tasks::timer;
+
+use super::{Block, SECTOR_SIZE, check_bounds};
+
+// 4 MiB of cached sectors
+pub const CACHE_SECTORS: usize = 8192;
+// Write back before a quarter of the cache is dirty
+const DIRTY_LIMIT: usize = CACHE_SECTORS / 4;
+// Sectors read beyond the end of a sequential read
+const READ_AHEAD_SECTORS: u64 = 64;
+// Largest single write issued during write-back
+const WRITE_BACK_SECTORS: usize = 128;
+const WRITE_BACK_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Entry {
+    data: Box<[u8; SECTOR_SIZE]>,
+    dirty: bool,
+    // Key in the LRU list
+    used: u64,
+    // Changed on every write, to detect writes during write-back
+    version: u64
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub dirty: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub read_ahead: u64,
+    pub written_back: u64
+}
+
+struct CacheState {
+    entries: BTreeMap<u64, Entry>,
+    // Sectors ordered from least to most recently used
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+    // Sector following the previous read, to detect sequential reads
+    next_read: u64,
+    // Changed before and after sectors are discarded, so reads meanwhile don't cache stale data
+    invalidations: u64,
+    stats: CacheStats
+}
+
+/// Write-back cache of sectors, shared by all users of the underlying device.
+pub struct BlockCache {
+    block: Arc<dyn Block>,
+    state: Mutex<CacheState>,
+    // Held while writing back, so an earlier write can't land on sectors discarded meanwhile
+    write_lock: AsyncMutex<()>
+}
+
+impl CacheState {
+    fn touch(&mut self, sector: u64) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(&sector) {
+            self.lru.remove(&entry.used);
+            entry.used = clock;
+            self.lru.insert(clock, sector);
+        }
+    }
+
+    fn get(&mut self, sector: u64, buf: &mut [u8]) -> bool {
+        match self.entries.get(&sector) {
+            Some(entry) => {
+                buf.copy_from_slice(&entry.data[..]);
+                self.stats.hits += 1;
+                self.touch(sector);
+                true
+            },
+            None => {
+                self.stats.misses += 1;
+                false
+            }
+        }
+    }
+
+    // Add a sector read from the device, unless a newer version is already cached
+    fn fill(&mut self, sector: u64, data: &[u8]) {
+        if !self.entries.contains_key(&sector) {
+            self.insert(sector, data, false);
+        }
+    }
+
+    fn write(&mut self, sector: u64, data: &[u8]) {
+        match self.entries.get_mut(&sector) {
+            Some(entry) => {
+                entry.data.copy_from_slice(data);
+                entry.version += 1;
+                if !entry.dirty {
+                    entry.dirty = true;
+                    self.stats.dirty += 1;
+                }
+                self.touch(sector);
+            },
+            None => self.insert(sector, data, true)
+        }
+    }
+
+    fn insert(&mut self, sector: u64, data: &[u8], dirty: bool) {
+        self.clock += 1;
+        self.entries.insert(sector, Entry {
+            data: Box::new(data.try_into().unwrap()),
+            dirty,
+            used: self.clock,
+            version: 0
+        });
+        self.lru.insert(self.clock, sector);
+        if dirty {
+            self.stats.dirty += 1;
+        }
+
+        self.evict();
+    }
+
+    // Drop least recently used clean sectors, dirty ones have to be written back first
+    fn evict(&mut self) {
+        while self.entries.len() > CACHE_SECTORS {
+            let Some(sector) = self.lru.values().copied().find(|sector| !self.entries[sector].dirty) else {
+                break;
+            };
+
+            let entry = self.entries.remove(&sector).unwrap();
+            self.lru.remove(&entry.used);
+        }
+    }
+
+    fn invalidate(&mut self, sector: u64, count: u64) {
+        self.invalidations += 1;
+        let sectors: Vec<u64> = self.entries.range(sector..sector + count).map(|(sector, _)| *sector).collect();
+        for sector in sectors {
+            let entry = self.entries.remove(&sector).unwrap();
+            self.lru.remove(&entry.used);
+            if entry.dirty {
+                self.stats.dirty -= 1;
+            }
+        }
+    }
+}
+
+impl BlockCache {
+    pub fn new(block: Arc<dyn Block>) -> Self {
+        Self {
+            block,
+            state: Mutex::new(CacheState {
+                entries: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                next_read: 0,
+                invalidations: 0,
+                stats: CacheStats::default()
+            }),
+            write_lock: AsyncMutex::new(())
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock();
+        CacheStats {
+            entries: state.entries.len(),
+            ..state.stats
+        }
+    }
+
+    /// Write all dirty sectors to the device.
+    pub async fn write_back(&self) -> Result<(), &'static str> {
+        let _guard = self.write_lock.lock().await;
+        let dirty: Vec<(u64, u64, Box<[u8; SECTOR_SIZE]>)> = self.state.lock().entries.iter()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(sector, entry)| (*sector, entry.version, entry.data.clone()))
+            .collect();
+
+        // Write runs of consecutive sectors with a single request
+        let mut start = 0;
+        while start < dirty.len() {
+            let mut end = start + 1;
+            while end < dirty.len() && dirty[end].0 == dirty[end - 1].0 + 1 && end - start < WRITE_BACK_SECTORS {
+                end += 1;
+            }
+
+            let mut data = Vec::with_capacity((end - start) * SECTOR_SIZE);
+            dirty[start..end].iter().for_each(|(_, _, sector)| data.extend_from_slice(&sector[..]));
+            self.block.write(&data, dirty[start].0).await?;
+
+            // Sectors written in the meantime stay dirty
+            let mut state = self.state.lock();
+            for (sector, version, _) in &dirty[start..end] {
+                if let Some(entry) = state.entries.get_mut(sector).filter(|entry| entry.dirty && entry.version == *version) {
+                    entry.dirty = false;
+                    state.stats.dirty -= 1;
+                    state.stats.written_back += 1;
+                }
+            }
+            state.evict();
+
+            start = end;
+        }
+        Ok(())
+    }
+
+    fn check_request(&self, len: usize, sector: u64) -> Result<u64, &'static str> {
+        if len % SECTOR_SIZE != 0 {
+            return Err("Buffer is not a multiple of the sector size");
+        }
+
+        let count = (len / SECTOR_SIZE) as u64;
+        check_bounds(sector, count, self.sector_count())?;
+        Ok(count)
+    }
+}
+
+impl Resource for BlockCache {}
+
+#[async_trait]
+impl Block for BlockCache {
+    fn sector_size(&self) -> usize {
+        self.block.sector_size()
+    }
+
+    fn block_size(&self) -> usize {
+        self.block.block_size()
+    }
+
+    fn read_only(&self) -> bool {
+        self.block.read_only()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.block.sector_count()
+    }
+
+    async fn read(&self, buf: &mut [u8], sector: u64) -> Result<(), &'static str> {
+        let count = self.check_request(buf.len(), sector)?;
+
+        let (misses, sequential) = {
+            let mut state = self.state.lock();
+            let sequential = state.next_read == sector;
+            state.next_read = sector + count;
+
+            let misses: Vec<u64> = buf.chunks_mut(SECTOR_SIZE).zip(0..count)
+                .filter_map(|(chunk, i)| (!state.get(sector + i, chunk)).then_some(i))
+                .collect();
+            (misses, sequential)
+        };
+
+        // Fetch runs of missing sectors with a single request
+        let mut start = 0;
+        while start < misses.len() {
+            let mut end = start + 1;
+            while end < misses.len() && misses[end] == misses[end - 1] + 1 {
+                end += 1;
+            }
+
+            let first = misses[start];
+            let len = (end - start) as u64;
+            let read_ahead = match sequential && first + len == count {
+                true => READ_AHEAD_SECTORS.min(self.sector_count() - sector - count),
+                false => 0
+            };
+
+            let mut data = vec![0u8; (len + read_ahead) as usize * SECTOR_SIZE];
+            let invalidations = self.state.lock().invalidations;
+            self.block.read(&mut data, sector + first).await?;
+
+            let mut state = self.state.lock();
+            let stale = state.invalidations != invalidations;
+            for (i, chunk) in data.chunks(SECTOR_SIZE).enumerate() {
+                let current = first + i as u64;
+                if !stale {
+                    state.fill(sector + current, chunk);
+                }
+
+                // Prefer sectors written while reading
+                if current < count {
+                    let entry = state.entries.get(&(sector + current));
+                    let source = entry.map_or(chunk, |entry| &entry.data[..]);
+                    buf[current as usize * SECTOR_SIZE..][..SECTOR_SIZE].copy_from_slice(source);
+                }
+            }
+            state.stats.read_ahead += read_ahead;
+
+            start = end;
+        }
+        Ok(())
+    }
+
+    async fn write(&self, buf: &[u8], sector: u64) -> Result<(), &'static str> {
+        self.check_request(buf.len(), sector)?;
+
+        let dirty = {
+            let mut state = self.state.lock();
+            buf.chunks(SECTOR_SIZE).zip(sector..).for_each(|(chunk, sector)| state.write(sector, chunk));
+            state.stats.dirty
+        };
+
+        if dirty > DIRTY_LIMIT {
+            self.write_back().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), &'static str> {
+        self.write_back().await?;
+        self.block.flush().await
+    }
+
+    async fn discard(&self, sector: u64, count: u64) -> Result<(), &'static str> {
+        check_bounds(sector, count, self.sector_count())?;
+        let _guard = self.write_lock.lock().await;
+        self.state.lock().invalidate(sector, count);
+        let result = self.block.discard(sector, count).await;
+        self.state.lock().invalidations += 1;
+        result
+    }
+
+    async fn write_zeroes(&self, sector: u64, count: u64) -> Result<(), &'static str> {
+        check_bounds(sector, count, self.sector_count())?;
+        let _guard = self.write_lock.lock().await;
+        self.state.lock().invalidate(sector, count);
+        let result = self.block.write_zeroes(sector, count).await;
+        self.state.lock().invalidations += 1;
+        result
+    }
+}
+
+/// Periodically write back dirty sectors of all disks.
+pub async fn write_back_periodically() {
+    loop {
+        timer::sleep(WRITE_BACK_INTERVAL).await;
+        for (name, cache) in runtime().block_devices.caches() {
+            if let Err(err) = cache.write_back().await {
+                writeln!(runtime().console.lock(), "{}: cache write-back failed: {}", name, err).unwrap();
+            }
+        }
+    }
+}
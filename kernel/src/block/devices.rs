@@ -0,0 +1,166 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use core::fmt::Write;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+
+use futures_util::StreamExt;
+
+use spin::{Mutex, RwLock};
+
+use crate::drivers::registry::{Device, DeviceClass, DeviceEvent, DeviceInfo};
+use crate::runtime::runtime;
+
+use super::Block;
+use super::cache::BlockCache;
+use super::gpt::Gpt;
+use super::mbr::Mbr;
+use super::partition::Partition;
+
+/// Disks with their buffer caches, scanned for partitions once registered.
+pub struct BlockDevices {
+    caches: RwLock<Vec<(String, Arc<BlockCache>)>>,
+    scans: Mutex<Scans>
+}
+
+struct Scans {
+    // Whether a scanned disk has no partition table
+    unpartitioned: BTreeMap<String, bool>,
+    waiters: Vec<Waker>
+}
+
+impl BlockDevices {
+    pub fn new() -> Self {
+        Self {
+            caches: RwLock::new(Vec::new()),
+            scans: Mutex::new(Scans {
+                unpartitioned: BTreeMap::new(),
+                waiters: Vec::new()
+            })
+        }
+    }
+
+    /// Register a disk behind a buffer cache.
+    pub fn add_disk(&self, name: &str, disk: Arc<dyn Block>) -> Result<(), &'static str> {
+        let cache = Arc::new(BlockCache::new(disk));
+        runtime().devices.add(name, None, Device::Block(cache.clone()))?;
+        self.caches.write().push((name.to_string(), cache));
+        Ok(())
+    }
+
+    pub fn caches(&self) -> Vec<(String, Arc<BlockCache>)> {
+        self.caches.read().clone()
+    }
+
+    /// Wait for the partition scan of a disk, returning whether a filesystem can cover the whole disk.
+    pub async fn unpartitioned(&self, disk: &str) -> bool {
+        poll_fn(|cx| {
+            let mut scans = self.scans.lock();
+            match scans.unpartitioned.get(disk) {
+                Some(unpartitioned) => Poll::Ready(*unpartitioned),
+                // Removed before it was scanned
+                None if runtime().devices.get(DeviceClass::Block, disk).is_none() => Poll::Ready(false),
+                None => {
+                    scans.waiters.push(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }).await
+    }
+
+    /// Scan registered disks for partitions, which get registered as devices of their own.
+    pub async fn run(&self) {
+        let mut events = runtime().devices.subscribe(DeviceClass::Block);
+        while let Some(event) = events.next().await {
+            match event {
+                DeviceEvent::Added(info) => self.added(info).await,
+                DeviceEvent::Removed(info) => {
+                    self.caches.write().retain(|(name, _)| *name != info.name);
+                    self.finish_scan(&info.name, None);
+                }
+            }
+        }
+    }
+
+    async fn added(&self, info: DeviceInfo) {
+        // Partitions don't contain partition tables themselves
+        let (Some(block), None) = (info.block(), &info.parent) else {
+            return;
+        };
+
+        let unpartitioned = match find_partitions(block).await {
+            Ok(partitions) => {
+                let unpartitioned = partitions.is_empty();
+                for (number, partition) in partitions {
+                    let name = partition_name(&info.name, number);
+                    if let Err(err) = runtime().devices.add(&name, Some(&info.name), Device::Block(Arc::new(partition))) {
+                        writeln!(runtime().console.lock(), "{}: {}", name, err).unwrap();
+                    }
+                }
+                unpartitioned
+            },
+            Err(err) => {
+                writeln!(runtime().console.lock(), "{}: {}", info.name, err).unwrap();
+                false
+            }
+        };
+        self.finish_scan(&info.name, Some(unpartitioned));
+    }
+
+    // Record the result of a scan, or forget it with `None` once the disk is removed
+    fn finish_scan(&self, disk: &str, unpartitioned: Option<bool>) {
+        let mut scans = self.scans.lock();
+        match unpartitioned {
+            Some(unpartitioned) => scans.unpartitioned.insert(disk.to_string(), unpartitioned),
+            None => scans.unpartitioned.remove(disk)
+        };
+        scans.waiters.drain(..).for_each(Waker::wake);
+    }
+}
+
+// Partitions numbered from one, from the GPT when the MBR only protects it
+async fn find_partitions(disk: Arc<dyn Block>) -> Result<Vec<(usize, Partition)>, &'static str> {
+    let mbr = Mbr::new(disk.clone()).await?;
+    if !mbr.is_valid() {
+        return Ok(Vec::new());
+    }
+
+    if mbr.is_protective() {
+        let gpt = Gpt::new(disk).await?;
+        return Ok(gpt.partitions().iter()
+            .filter_map(|partition| Some((partition.index + 1, gpt.get_partition(partition.index).ok()?)))
+            .collect());
+    }
+
+    let mut partitions = Vec::new();
+    for index in 0..mbr.partitions().len() {
+        if let Ok(partition) = mbr.get_partition(index).await {
+            partitions.push((index + 1, partition));
+        }
+    }
+    Ok(partitions)
+}
+
+// Separate the number when the disk name already ends in one, like ram0p1
+fn partition_name(disk: &str, number: usize) -> String {
+    match disk.ends_with(|c: char| c.is_ascii_digit()) {
+        true => format!("{}p{}", disk, number),
+        false => format!("{}{}", disk, number)
+    }
+}
+
+/// Name of the disk with the given index, counting from `a` after the prefix.
+pub fn disk_name(prefix: &str, index: usize) -> String {
+    let mut suffix = String::new();
+    let mut index = index + 1;
+    while index > 0 {
+        index -= 1;
+        suffix.insert(0, (b'a' + (index % 26) as u8) as char);
+        index /= 26;
+    }
+    format!("{}{}", prefix, suffix)
+}
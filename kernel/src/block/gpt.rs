@@ -0,0 +1,225 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use core::fmt;
+use core::mem::size_of;
+
+use super::{Block, SECTOR_SIZE, sectors};
+use super::mbr::Mbr;
+use super::partition::Partition;
+
+const SIGNATURE: [u8; 8] = *b"EFI PART";
+const PRIMARY_HEADER_LBA: u64 = 1;
+// Offset of the header CRC, which is zero while calculating it
+const HEADER_CRC_OFFSET: usize = 16;
+// Refuse unreasonably large entry arrays
+const MAX_ENTRIES_SIZE: usize = 1024 * 1024;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    pub const UNUSED: Guid = Guid::new(0, 0, 0, [0; 8]);
+    pub const EFI_SYSTEM: Guid = Guid::new(0xC12A7328, 0xF81F, 0x11D2, [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B]);
+    pub const BIOS_BOOT: Guid = Guid::new(0x21686148, 0x6449, 0x6E6F, [0x74, 0x4E, 0x65, 0x65, 0x64, 0x45, 0x46, 0x49]);
+    pub const BASIC_DATA: Guid = Guid::new(0xEBD0A0A2, 0xB9E5, 0x4433, [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7]);
+    pub const LINUX_FILESYSTEM: Guid = Guid::new(0x0FC63DAF, 0x8483, 0x4772, [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4]);
+    pub const LINUX_SWAP: Guid = Guid::new(0x0657FD6D, 0xA4AB, 0x43C4, [0x84, 0xE5, 0x09, 0x33, 0xC8, 0x4B, 0x4F, 0x4F]);
+
+    // The first three fields are stored little endian
+    pub const fn new(a: u32, b: u16, c: u16, d: [u8; 8]) -> Self {
+        let a = a.to_le_bytes();
+        let b = b.to_le_bytes();
+        let c = c.to_le_bytes();
+        Self([a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]])
+    }
+
+    /// Name of well known partition types.
+    pub fn type_name(&self) -> Option<&'static str> {
+        match *self {
+            Guid::EFI_SYSTEM => Some("EFI System"),
+            Guid::BIOS_BOOT => Some("BIOS boot"),
+            Guid::BASIC_DATA => Some("Basic data"),
+            Guid::LINUX_FILESYSTEM => Some("Linux filesystem"),
+            Guid::LINUX_SWAP => Some("Linux swap"),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(f, "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]), u16::from_le_bytes([b[4], b[5]]), u16::from_le_bytes([b[6], b[7]]), b[8], b[9])?;
+        b[10..].iter().try_for_each(|byte| write!(f, "{:02X}", byte))
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// GPT header.
+/// See UEFI specification 2.10 - 5.3.2
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+struct GptHeader {
+    signature: [u8; 8],
+    revision: u32,
+    header_size: u32,
+    header_crc32: u32,
+    reserved: u32,
+    current_lba: u64,
+    backup_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    disk_guid: Guid,
+    entries_lba: u64,
+    num_entries: u32,
+    entry_size: u32,
+    entries_crc32: u32
+}
+
+/// GPT partition entry.
+/// See UEFI specification 2.10 - 5.3.3
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+struct GptEntry {
+    type_guid: Guid,
+    unique_guid: Guid,
+    first_lba: u64,
+    last_lba: u64,
+    attributes: u64,
+    name: [u16; 36]
+}
+
+#[derive(Debug, Clone)]
+pub struct GptPartition {
+    pub index: usize,
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: String
+}
+
+pub struct Gpt {
+    disk_guid: Guid,
+    partitions: Vec<GptPartition>,
+    block: Arc<dyn Block>,
+}
+
+impl Gpt {
+    /// Read the partition table, using the backup when the primary one is damaged.
+    pub async fn new(block: Arc<dyn Block>) -> Result<Self, &'static str> {
+        if !Mbr::new(block.clone()).await?.is_protective() {
+            return Err("No protective MBR");
+        }
+
+        let last_lba = block.sector_count().checked_sub(1).ok_or("Device is empty")?;
+        let (header, entries) = match read_table(block.as_ref(), PRIMARY_HEADER_LBA).await {
+            Ok(table) => table,
+            Err(_) => read_table(block.as_ref(), last_lba).await?
+        };
+
+        let partitions = entries.iter().enumerate()
+            .filter(|(_, entry)| entry.type_guid != Guid::UNUSED)
+            .map(|(index, entry)| GptPartition {
+                index,
+                type_guid: entry.type_guid,
+                unique_guid: entry.unique_guid,
+                first_lba: entry.first_lba,
+                last_lba: entry.last_lba,
+                attributes: entry.attributes,
+                name: char::decode_utf16({ entry.name }.into_iter().take_while(|c| *c != 0))
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect()
+            })
+            .collect();
+
+        Ok(Self {
+            disk_guid: header.disk_guid,
+            partitions,
+            block
+        })
+    }
+
+    pub fn disk_guid(&self) -> Guid {
+        self.disk_guid
+    }
+
+    pub fn partitions(&self) -> &[GptPartition] {
+        &self.partitions
+    }
+
+    /// Partition with the given entry index.
+    pub fn get_partition(&self, index: usize) -> Result<Partition, &'static str> {
+        let partition = self.partitions.iter().find(|partition| partition.index == index).ok_or("Partition not in use")?;
+        if partition.last_lba < partition.first_lba {
+            return Err("Invalid partition range");
+        }
+
+        Partition::new(self.block.clone(), partition.first_lba, partition.last_lba - partition.first_lba + 1)
+    }
+}
+
+// Read and validate the header at `lba` and its entry array
+async fn read_table(block: &dyn Block, lba: u64) -> Result<(GptHeader, Vec<GptEntry>), &'static str> {
+    let mut buf = [0u8; SECTOR_SIZE];
+    block.read(&mut buf, lba).await?;
+    let header: GptHeader = unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const GptHeader) };
+
+    let header_size = header.header_size as usize;
+    if header.signature != SIGNATURE || header_size < size_of::<GptHeader>() || header_size > SECTOR_SIZE {
+        return Err("Invalid GPT header");
+    }
+
+    buf[HEADER_CRC_OFFSET..HEADER_CRC_OFFSET + 4].fill(0);
+    if crc32(&buf[..header_size]) != header.header_crc32 || header.current_lba != lba {
+        return Err("GPT header checksum mismatch");
+    }
+
+    let entry_size = header.entry_size as usize;
+    let entries_size = header.num_entries as usize * entry_size;
+    if entry_size < size_of::<GptEntry>() || entry_size % 8 != 0 || entries_size > MAX_ENTRIES_SIZE {
+        return Err("Invalid GPT entry array");
+    }
+
+    let mut data = vec![0u8; sectors(entries_size) as usize * SECTOR_SIZE];
+    block.read(&mut data, header.entries_lba).await?;
+    if crc32(&data[..entries_size]) != header.entries_crc32 {
+        return Err("GPT entry array checksum mismatch");
+    }
+
+    let entries = data[..entries_size].chunks(entry_size)
+        .map(|entry| unsafe { core::ptr::read_unaligned(entry.as_ptr() as *const GptEntry) })
+        .collect();
+    Ok((header, entries))
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
@@ -0,0 +1,75 @@
+use alloc::boxed::Box;
+
+use async_trait::async_trait;
+
+use crate::fs::File;
+use crate::runtime::Resource;
+
+use super::{Block, SECTOR_SIZE, check_bounds};
+
+/// File exposed as a block device, like a disk image stored on another filesystem.
+pub struct LoopDevice {
+    file: Box<dyn File>
+}
+
+impl LoopDevice {
+    pub fn new(file: Box<dyn File>) -> Self {
+        Self {
+            file
+        }
+    }
+
+    fn check_request(&self, len: usize, sector: u64) -> Result<u64, &'static str> {
+        if len % SECTOR_SIZE != 0 {
+            return Err("Buffer is not a multiple of the sector size");
+        }
+
+        check_bounds(sector, (len / SECTOR_SIZE) as u64, self.sector_count())?;
+        Ok(sector * SECTOR_SIZE as u64)
+    }
+}
+
+impl Resource for LoopDevice {}
+
+#[async_trait]
+impl Block for LoopDevice {
+    fn read_only(&self) -> bool {
+        self.file.read_only()
+    }
+
+    // A trailing partial sector of the file is not accessible
+    fn sector_count(&self) -> u64 {
+        self.file.stat().size / SECTOR_SIZE as u64
+    }
+
+    async fn read(&self, buf: &mut [u8], sector: u64) -> Result<(), &'static str> {
+        let offset = self.check_request(buf.len(), sector)?;
+
+        // Files may return less than requested, for example at cluster boundaries
+        let mut done = 0;
+        while done < buf.len() {
+            match self.file.read_at(&mut buf[done..], offset + done as u64).await.map_err(|err| err.as_str())? {
+                0 => return Err("Unexpected end of file"),
+                len => done += len
+            }
+        }
+        Ok(())
+    }
+
+    async fn write(&self, buf: &[u8], sector: u64) -> Result<(), &'static str> {
+        let offset = self.check_request(buf.len(), sector)?;
+
+        let mut done = 0;
+        while done < buf.len() {
+            match self.file.write_at(&buf[done..], offset + done as u64).await.map_err(|err| err.as_str())? {
+                0 => return Err("Unexpected end of file"),
+                len => done += len
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), &'static str> {
+        self.file.flush().await.map_err(|err| err.as_str())
+    }
+}
@@ -1,11 +1,11 @@
-use alloc::boxed::Box;
 use alloc::sync::Arc;
 
-use async_trait::async_trait;
-
-use crate::runtime::Resource;
-
 use super::Block;
+use super::partition::Partition;
+
+const SIGNATURE: u16 = 0xAA55;
+// Partition type covering the whole disk, marking it as GPT formatted
+const PROTECTIVE_TYPE: u8 = 0xEE;
 
 #[repr(C, packed)]
 pub struct MbrHeader {
@@ -46,34 +46,44 @@ impl Mbr {
         self.header.partitions
     }
 
-    pub async fn get_partition(&self, index: usize) -> Result<MbrPartition, &'static str> {
-        if index > self.header.partitions.len() {
-            return Err("Partition index out of bounds");
+    /// Whether the sector holds a partition table rather than for example a FAT boot sector.
+    pub fn is_valid(&self) -> bool {
+        let sector_count = self.block.sector_count();
+        self.header.signature == SIGNATURE && self.partitions().iter().all(|entry| {
+            (entry.boot_indicator == 0 || entry.boot_indicator == 0x80)
+                && (entry.is_empty() || entry.start() + entry.size() <= sector_count || entry.partition_type == PROTECTIVE_TYPE)
+        })
+    }
+
+    /// Whether the MBR only protects a GPT partition table.
+    pub fn is_protective(&self) -> bool {
+        self.partitions().iter().any(|entry| entry.partition_type == PROTECTIVE_TYPE)
+    }
+
+    pub async fn get_partition(&self, index: usize) -> Result<Partition, &'static str> {
+        let entry = self.header.partitions.get(index).ok_or("Partition index out of bounds")?;
+        if entry.is_empty() {
+            return Err("Partition not in use");
         }
 
-        Ok(MbrPartition::new(self.header.partitions[index], self.block.clone()).await?)
+        Partition::new(self.block.clone(), entry.starting_lba as u64, entry.size_in_sectors as u64)
     }
 }
 
-pub struct MbrPartition {
-    entry: PartitionEntry,
-    block: Arc<dyn Block>,
-}
+impl PartitionEntry {
+    pub fn partition_type(&self) -> u8 {
+        self.partition_type
+    }
 
-impl MbrPartition {
-    pub async fn new(entry: PartitionEntry, block: Arc<dyn Block>) -> Result<Self, &'static str> {
-        Ok(Self {
-            entry,
-            block
-        })
+    pub fn start(&self) -> u64 {
+        self.starting_lba as u64
     }
-}
 
-impl Resource for MbrPartition {}
+    pub fn size(&self) -> u64 {
+        self.size_in_sectors as u64
+    }
 
-#[async_trait]
-impl Block for MbrPartition {
-    async fn read(&self, buf: &mut [u8], sector: u64) -> Result<(), &'static str> {
-        self.block.read(buf, sector + self.entry.starting_lba as u64).await
+    pub fn is_empty(&self) -> bool {
+        self.partition_type == 0 || self.size_in_sectors == 0
     }
 }
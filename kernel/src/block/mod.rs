@@ -1,12 +1,79 @@
 use alloc::boxed::Box;
+use alloc::vec;
 
 use async_trait::async_trait;
 
 use crate::runtime::Resource;
 
+pub mod cache;
+pub mod devices;
+pub mod gpt;
+pub mod loopdev;
 pub mod mbr;
+pub mod partition;
+pub mod ramdisk;
+
+pub const SECTOR_SIZE: usize = 512;
+
+// Largest write issued when emulating write zeroes
+const ZEROES_CHUNK_SECTORS: u64 = 64;
 
 #[async_trait]
 pub trait Block: Resource {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64;
+
+    /// Preferred size of requests in bytes.
+    fn block_size(&self) -> usize {
+        self.sector_size()
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
     async fn read(&self, buf: &mut [u8], sector: u64) -> Result<(), &'static str>;
+    async fn write(&self, buf: &[u8], sector: u64) -> Result<(), &'static str>;
+
+    /// Wait until all completed writes are persisted.
+    async fn flush(&self) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Hint that the sectors are no longer in use, their content is undefined afterwards.
+    async fn discard(&self, _sector: u64, _count: u64) -> Result<(), &'static str> {
+        Err("Discard not supported")
+    }
+
+    async fn write_zeroes(&self, sector: u64, count: u64) -> Result<(), &'static str> {
+        fill_zeroes(self, sector, count).await
+    }
+}
+
+/// Write zeroes using regular writes, for devices without a dedicated command.
+pub async fn fill_zeroes<B: Block + ?Sized>(block: &B, sector: u64, count: u64) -> Result<(), &'static str> {
+    let buf = vec![0u8; ZEROES_CHUNK_SECTORS as usize * SECTOR_SIZE];
+    let mut done = 0;
+    while done < count {
+        let chunk = (count - done).min(ZEROES_CHUNK_SECTORS);
+        block.write(&buf[..chunk as usize * SECTOR_SIZE], sector + done).await?;
+        done += chunk;
+    }
+    Ok(())
+}
+
+/// Number of sectors touched by a buffer of `len` bytes.
+pub fn sectors(len: usize) -> u64 {
+    ((len + SECTOR_SIZE - 1) / SECTOR_SIZE) as u64
+}
+
+/// Check that `count` sectors starting at `sector` lie within a device of `sector_count` sectors.
+pub fn check_bounds(sector: u64, count: u64, sector_count: u64) -> Result<(), &'static str> {
+    match sector.checked_add(count) {
+        Some(end) if end <= sector_count => Ok(()),
+        _ => Err("Sector out of bounds")
+    }
 }
@@ -0,0 +1,77 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::runtime::Resource;
+
+use super::{Block, check_bounds, sectors};
+
+/// Range of sectors of a device, exposed as a device of its own.
+pub struct Partition {
+    start: u64,
+    count: u64,
+    block: Arc<dyn Block>,
+}
+
+impl Partition {
+    pub fn new(block: Arc<dyn Block>, start: u64, count: u64) -> Result<Self, &'static str> {
+        check_bounds(start, count, block.sector_count())?;
+        Ok(Self {
+            start,
+            count,
+            block
+        })
+    }
+
+    // Translate to a sector of the underlying device, rejecting access outside the partition
+    fn translate(&self, sector: u64, count: u64) -> Result<u64, &'static str> {
+        check_bounds(sector, count, self.count)?;
+        Ok(sector + self.start)
+    }
+}
+
+impl Resource for Partition {}
+
+#[async_trait]
+impl Block for Partition {
+    fn sector_size(&self) -> usize {
+        self.block.sector_size()
+    }
+
+    fn block_size(&self) -> usize {
+        self.block.block_size()
+    }
+
+    fn read_only(&self) -> bool {
+        self.block.read_only()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.count
+    }
+
+    async fn read(&self, buf: &mut [u8], sector: u64) -> Result<(), &'static str> {
+        let sector = self.translate(sector, sectors(buf.len()))?;
+        self.block.read(buf, sector).await
+    }
+
+    async fn write(&self, buf: &[u8], sector: u64) -> Result<(), &'static str> {
+        let sector = self.translate(sector, sectors(buf.len()))?;
+        self.block.write(buf, sector).await
+    }
+
+    async fn flush(&self) -> Result<(), &'static str> {
+        self.block.flush().await
+    }
+
+    async fn discard(&self, sector: u64, count: u64) -> Result<(), &'static str> {
+        let sector = self.translate(sector, count)?;
+        self.block.discard(sector, count).await
+    }
+
+    async fn write_zeroes(&self, sector: u64, count: u64) -> Result<(), &'static str> {
+        let sector = self.translate(sector, count)?;
+        self.block.write_zeroes(sector, count).await
+    }
+}
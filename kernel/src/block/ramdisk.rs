@@ -0,0 +1,108 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use core::ops::{Deref, DerefMut, Range};
+
+use async_trait::async_trait;
+
+use spin::RwLock;
+
+use crate::runtime::Resource;
+
+use super::{Block, SECTOR_SIZE, check_bounds};
+
+enum Memory {
+    Heap(Box<[u8]>),
+    // Loaded by the bootloader and never freed
+    Static(&'static mut [u8])
+}
+
+/// Block device keeping its content in memory.
+pub struct RamDisk {
+    memory: RwLock<Memory>
+}
+
+impl RamDisk {
+    /// Zero filled disk of `size` bytes, rounded up to whole sectors.
+    pub fn new(size: usize) -> Result<Self, &'static str> {
+        let size = (size + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE;
+        let mut data = Vec::new();
+        data.try_reserve_exact(size).map_err(|_| "Out of memory")?;
+        data.resize(size, 0);
+
+        Ok(Self {
+            memory: RwLock::new(Memory::Heap(data.into_boxed_slice()))
+        })
+    }
+
+    /// Disk backed by memory that stays valid forever, like an image loaded by the bootloader.
+    /// A trailing partial sector is ignored.
+    pub unsafe fn from_static(address: usize, len: usize) -> Self {
+        let data = core::slice::from_raw_parts_mut(address as *mut u8, len / SECTOR_SIZE * SECTOR_SIZE);
+        Self {
+            memory: RwLock::new(Memory::Static(data))
+        }
+    }
+
+    fn range(&self, len: usize, sector: u64) -> Result<Range<usize>, &'static str> {
+        if len % SECTOR_SIZE != 0 {
+            return Err("Buffer is not a multiple of the sector size");
+        }
+
+        check_bounds(sector, (len / SECTOR_SIZE) as u64, self.sector_count())?;
+        let start = sector as usize * SECTOR_SIZE;
+        Ok(start..start + len)
+    }
+}
+
+impl Deref for Memory {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Memory::Heap(data) => data,
+            Memory::Static(data) => data
+        }
+    }
+}
+
+impl DerefMut for Memory {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Memory::Heap(data) => data,
+            Memory::Static(data) => data
+        }
+    }
+}
+
+impl Resource for RamDisk {}
+
+#[async_trait]
+impl Block for RamDisk {
+    fn sector_count(&self) -> u64 {
+        (self.memory.read().len() / SECTOR_SIZE) as u64
+    }
+
+    async fn read(&self, buf: &mut [u8], sector: u64) -> Result<(), &'static str> {
+        let range = self.range(buf.len(), sector)?;
+        buf.copy_from_slice(&self.memory.read()[range]);
+        Ok(())
+    }
+
+    async fn write(&self, buf: &[u8], sector: u64) -> Result<(), &'static str> {
+        let range = self.range(buf.len(), sector)?;
+        self.memory.write()[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    async fn discard(&self, sector: u64, count: u64) -> Result<(), &'static str> {
+        self.write_zeroes(sector, count).await
+    }
+
+    async fn write_zeroes(&self, sector: u64, count: u64) -> Result<(), &'static str> {
+        check_bounds(sector, count, self.sector_count())?;
+        let start = sector as usize * SECTOR_SIZE;
+        self.memory.write()[start..start + count as usize * SECTOR_SIZE].fill(0);
+        Ok(())
+    }
+}
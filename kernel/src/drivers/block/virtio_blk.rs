@@ -1,20 +1,40 @@
 use alloc::boxed::Box;
 use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 
-use async_trait::async_trait;
+use core::ptr;
 
-use spin::Mutex;
+use async_trait::async_trait;
 
+use crate::arch::PAGE_SIZE;
 use crate::arch::system::System;
-use crate::block::Block;
+use crate::block::{Block, SECTOR_SIZE, check_bounds, fill_zeroes, sectors};
 use crate::drivers::pci::PciDevice;
 use crate::drivers::virtio::pci::{DeviceStatus, VirtioPciDevice};
 use crate::drivers::virtio::virtq::{Virtq, Descriptor, VIRTQ_DESC_F_WRITE};
 use crate::runtime::{Resource, runtime};
 
+const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 1 << 14;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Request status written by the device, kept as a byte as the device may write any value
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const SUPPORTED_FEATURES: u64 = VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_RO | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_DISCARD | VIRTIO_BLK_F_WRITE_ZEROES | VIRTIO_F_VERSION_1;
+
 pub struct VirtioBlk {
     device: VirtioPciDevice<BlkConfig>,
-    queue: Arc<Mutex<Virtq>>
+    queue: Arc<Virtq>,
+    features: u64
 }
 
 impl Resource for VirtioBlk {}
@@ -31,6 +51,19 @@ pub struct BlkConfig {
     geometry_heads: u8,
     geometry_sectors: u8,
     blk_size: u32,
+    physical_block_exp: u8,
+    alignment_offset: u8,
+    min_io_size: u16,
+    opt_io_size: u32,
+    writeback: u8,
+    unused0: [u8; 3],
+    max_discard_sectors: u32,
+    max_discard_seg: u32,
+    discard_sector_alignment: u32,
+    max_write_zeroes_sectors: u32,
+    max_write_zeroes_seg: u32,
+    write_zeroes_may_unmap: u8,
+    unused1: [u8; 3],
 }
 
 #[allow(non_camel_case_types, dead_code)]
@@ -44,14 +77,6 @@ pub enum BlkRequestType {
     VIRTIO_BLK_T_WRITE_ZEROES = 13,
 }
 
-#[allow(non_camel_case_types, dead_code)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-#[repr(u8)]
-pub enum BlkRequestStatus {
-    VIRTIO_BLK_S_OK = 0,
-    VIRTIO_BLK_S_IOERR = 1,
-    VIRTIO_BLK_S_UNSUPP = 2,
-}
 
 #[derive(Debug, Copy, Clone)]
 #[repr(C)]
@@ -61,6 +86,47 @@ pub struct BlkRequest {
     sector: u64,
 }
 
+/// Segment of a discard or write zeroes request.
+/// See specification v1.1. - 5.2.6
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct BlkDiscardWriteZeroes {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+// Memory the device accesses during a request, owned by the queue until the device is done with it.
+// Aligned to its size, so none of the fields crosses a page boundary.
+#[repr(C, align(64))]
+struct BlkBuffers {
+    header: BlkRequest,
+    segment: BlkDiscardWriteZeroes,
+    status: u8,
+    // Bounce buffer, the memory of the caller may be gone when the request is dropped
+    data: Vec<u8>
+}
+
+impl BlkBuffers {
+    // Header and status are filled in when submitting
+    fn new(data: Vec<u8>) -> Box<Self> {
+        Box::new(Self {
+            header: BlkRequest {
+                request_type: BlkRequestType::VIRTIO_BLK_T_IN,
+                reserved: 0,
+                sector: 0
+            },
+            segment: BlkDiscardWriteZeroes {
+                sector: 0,
+                num_sectors: 0,
+                flags: 0
+            },
+            status: VIRTIO_BLK_S_IOERR,
+            data
+        })
+    }
+}
+
 impl VirtioBlk {
     pub fn new(device: Arc<PciDevice>) -> Result<Self, &'static str> {
         let mut virtio = VirtioPciDevice::new(device)?;
@@ -68,8 +134,13 @@ impl VirtioBlk {
         virtio.set_device_status(DeviceStatus::ACKNOWLEDGE);
         virtio.set_device_status(DeviceStatus::DRIVER);
 
-        let _device_features = virtio.get_features();
+        let features = virtio.get_features() & SUPPORTED_FEATURES;
+        virtio.set_features(features);
         virtio.set_device_status(DeviceStatus::FEATURES_OK);
+        if virtio.get_device_status() & DeviceStatus::FEATURES_OK as u8 == 0 {
+            virtio.set_device_status(DeviceStatus::FAILED);
+            return Err("Device did not accept features");
+        }
 
         let mut queue_handler = virtio.get_virtq_handler(0).ok_or("Virtqueue not found!")?;
         queue_handler.set_msix_vector(0);
@@ -78,14 +149,12 @@ impl VirtioBlk {
 
         let mut device = VirtioBlk {
             device: virtio,
-            queue: Arc::new(Mutex::new(queue))
+            queue: Arc::new(queue),
+            features
         };
 
         let dev_queue = device.queue.clone();
-        let vector = runtime().system.request_irq_handler(Box::new(move || {
-            unsafe { dev_queue.force_unlock(); }
-            dev_queue.lock().process();
-        })).unwrap();
+        let vector = runtime().system.request_irq_handler(Box::new(move || dev_queue.process())).unwrap();
 
         let entries = device.device.msix.entries(device.device.pci.bars[1].unwrap().as_ptr() as usize);
 
@@ -102,27 +171,198 @@ impl VirtioBlk {
 
         Ok(device)
     }
+
+    fn check_request(&self, len: usize, sector: u64) -> Result<(), &'static str> {
+        if len % SECTOR_SIZE != 0 {
+            return Err("Buffer is not a multiple of the sector size");
+        }
+
+        check_bounds(sector, sectors(len), self.sector_count())
+    }
+
+    // Maximum size of a segment and number of segments in a request
+    fn limits(&self) -> (usize, usize) {
+        let config = self.device.device_config();
+        let size_max = match self.has_feature(VIRTIO_BLK_F_SIZE_MAX) {
+            true => config.size_max as usize,
+            false => u32::MAX as usize
+        };
+
+        // Header and status take up two descriptors of the chain
+        let mut seg_max = self.queue.size() - 2;
+        if self.has_feature(VIRTIO_BLK_F_SEG_MAX) {
+            seg_max = seg_max.min(config.seg_max as usize);
+        }
+
+        (size_max, seg_max)
+    }
+
+    fn has_feature(&self, feature: u64) -> bool {
+        self.features & feature != 0
+    }
+
+    fn check_writable(&self) -> Result<(), &'static str> {
+        match self.read_only() {
+            true => Err("Device is read-only"),
+            false => Ok(())
+        }
+    }
+
+    // Largest request that fits within the device limits wherever its buffer is in memory
+    fn max_request(&self) -> Result<usize, &'static str> {
+        let (size_max, seg_max) = self.limits();
+
+        // Segments end at page boundaries as well, each of those splits one more
+        let segment = size_max.min(PAGE_SIZE);
+        let max = seg_max.saturating_sub(2) / 2 * segment / SECTOR_SIZE * SECTOR_SIZE;
+        match max {
+            0 => Err("Request exceeds device limits"),
+            max => Ok(max)
+        }
+    }
+
+    async fn submit(&self, request_type: BlkRequestType, sector: u64, mut buffers: Box<BlkBuffers>, data: &[Descriptor]) -> Result<Box<BlkBuffers>, &'static str> {
+        buffers.header = BlkRequest {
+            request_type,
+            reserved: 0,
+            sector
+        };
+        buffers.status = VIRTIO_BLK_S_IOERR;
+
+        let mut descs = Vec::with_capacity(data.len() + 2);
+        descs.push(Descriptor::new(&mut buffers.header, 0));
+        descs.extend_from_slice(data);
+        descs.push(Descriptor::new(&mut buffers.status, VIRTQ_DESC_F_WRITE));
+
+        let (_, buffers) = self.queue.request(&descs, buffers).await?;
+
+        // Written by the device
+        match unsafe { ptr::read_volatile(&buffers.status) } {
+            VIRTIO_BLK_S_OK => Ok(buffers),
+            VIRTIO_BLK_S_UNSUPP => Err("Request not supported by device"),
+            _ => Err("I/O error")
+        }
+    }
+
+    // Transfer the bounce buffer in a single request of physically contiguous segments
+    async fn transfer(&self, request_type: BlkRequestType, data: Vec<u8>, sector: u64) -> Result<Box<BlkBuffers>, &'static str> {
+        let flags = match request_type {
+            BlkRequestType::VIRTIO_BLK_T_IN => VIRTQ_DESC_F_WRITE,
+            _ => 0
+        };
+        let (size_max, seg_max) = self.limits();
+
+        let mut segments: Vec<Descriptor> = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let address = data.as_ptr() as usize + offset;
+            let physical = runtime().system.translate(address).ok_or("Buffer not mapped")? as u64;
+            let chunk = (PAGE_SIZE - address % PAGE_SIZE).min(data.len() - offset).min(size_max);
+
+            let count = segments.len();
+            match segments.last_mut() {
+                Some(last) if last.address + last.len as u64 == physical && last.len as usize + chunk <= size_max => last.len += chunk as u32,
+                _ if count == seg_max => return Err("Request exceeds device limits"),
+                _ => segments.push(Descriptor::new_raw(physical, chunk, flags))
+            }
+            offset += chunk;
+        }
+
+        self.submit(request_type, sector, BlkBuffers::new(data), &segments).await
+    }
+
+    // Split the range in requests the device accepts
+    async fn submit_ranges(&self, request_type: BlkRequestType, sector: u64, count: u64, max_sectors: u32) -> Result<(), &'static str> {
+        let max_sectors = if max_sectors == 0 { u32::MAX } else { max_sectors } as u64;
+
+        let mut done = 0;
+        while done < count {
+            let chunk = (count - done).min(max_sectors);
+            let mut buffers = BlkBuffers::new(Vec::new());
+            buffers.segment = BlkDiscardWriteZeroes {
+                sector: sector + done,
+                num_sectors: chunk as u32,
+                flags: 0
+            };
+
+            let segment = Descriptor::new(&mut buffers.segment, 0);
+            self.submit(request_type, 0, buffers, &[segment]).await?;
+            done += chunk;
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl Block for VirtioBlk {
+    fn sector_count(&self) -> u64 {
+        // Capacity is always in 512 byte sectors
+        self.device.device_config().capacity
+    }
+
+    // Requests themselves always use 512 byte sectors
+    fn block_size(&self) -> usize {
+        match self.has_feature(VIRTIO_BLK_F_BLK_SIZE) {
+            true => self.device.device_config().blk_size as usize,
+            false => SECTOR_SIZE
+        }
+    }
+
+    fn read_only(&self) -> bool {
+        self.has_feature(VIRTIO_BLK_F_RO)
+    }
+
     async fn read(&self, buf: &mut [u8], sector: u64) -> Result<(), &'static str> {
-        let mut status = BlkRequestStatus::VIRTIO_BLK_S_IOERR;
+        self.check_request(buf.len(), sector)?;
 
-        let mut blk_request = Box::pin(BlkRequest {
-            request_type: BlkRequestType::VIRTIO_BLK_T_IN,
-            reserved: 0,
-            sector
-        });
+        let max_request = self.max_request()?;
+        for (index, chunk) in buf.chunks_mut(max_request).enumerate() {
+            let buffers = self.transfer(BlkRequestType::VIRTIO_BLK_T_IN, vec![0u8; chunk.len()], sector + sectors(index * max_request)).await?;
+            chunk.copy_from_slice(&buffers.data);
+        }
+        Ok(())
+    }
 
-        let descs = [
-            Descriptor::new(blk_request.as_mut().get_mut(), 0),
-            Descriptor::new_raw(buf.as_mut_ptr(), buf.len(), VIRTQ_DESC_F_WRITE),
-            Descriptor::new(&mut status, VIRTQ_DESC_F_WRITE),
-        ];
+    async fn write(&self, buf: &[u8], sector: u64) -> Result<(), &'static str> {
+        self.check_writable()?;
+        self.check_request(buf.len(), sector)?;
 
-        self.queue.lock().request(&descs).await.unwrap();
+        let max_request = self.max_request()?;
+        for (index, chunk) in buf.chunks(max_request).enumerate() {
+            self.transfer(BlkRequestType::VIRTIO_BLK_T_OUT, chunk.to_vec(), sector + sectors(index * max_request)).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), &'static str> {
+        // Without a volatile write cache all writes are persisted on completion
+        if !self.has_feature(VIRTIO_BLK_F_FLUSH) {
+            return Ok(());
+        }
 
+        self.transfer(BlkRequestType::VIRTIO_BLK_T_FLUSH, Vec::new(), 0).await?;
         Ok(())
     }
+
+    async fn discard(&self, sector: u64, count: u64) -> Result<(), &'static str> {
+        self.check_writable()?;
+        if !self.has_feature(VIRTIO_BLK_F_DISCARD) {
+            return Err("Discard not supported");
+        }
+        check_bounds(sector, count, self.sector_count())?;
+
+        let max_sectors = self.device.device_config().max_discard_sectors;
+        self.submit_ranges(BlkRequestType::VIRTIO_BLK_T_DISCARD, sector, count, max_sectors).await
+    }
+
+    async fn write_zeroes(&self, sector: u64, count: u64) -> Result<(), &'static str> {
+        self.check_writable()?;
+        check_bounds(sector, count, self.sector_count())?;
+        if !self.has_feature(VIRTIO_BLK_F_WRITE_ZEROES) {
+            return fill_zeroes(self, sector, count).await;
+        }
+
+        let max_sectors = self.device.device_config().max_write_zeroes_sectors;
+        self.submit_ranges(BlkRequestType::VIRTIO_BLK_T_WRITE_ZEROES, sector, count, max_sectors).await
+    }
 }
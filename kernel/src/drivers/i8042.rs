@@ -14,6 +14,8 @@ use spin::Mutex;
 
 use x86_64::instructions::port::Port;
 
+use crate::runtime::Resource;
+
 struct I8042 {
     data_port: Port<u8>,
     control_port: Port<u8>,
@@ -205,6 +207,8 @@ pub struct PcKeyboard {
     waker: AtomicWaker
 }
 
+impl Resource for PcKeyboard {}
+
 impl PcKeyboard {
     pub fn new() -> Self {
         Self {
@@ -1,5 +1,7 @@
 pub mod block;
 pub mod i8042;
 pub mod pci;
+pub mod registry;
+pub mod rtc;
 pub mod video;
 pub mod virtio;
@@ -0,0 +1,212 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use core::fmt;
+use core::pin::Pin;
+use core::str::FromStr;
+use core::task::{Context, Poll};
+
+use futures_util::Stream;
+use futures_util::task::AtomicWaker;
+
+use spin::{Mutex, RwLock};
+
+use crate::block::Block;
+use crate::runtime::Resource;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeviceClass {
+    Block,
+    Char,
+    Net,
+    Display,
+    Input
+}
+
+#[derive(Clone)]
+pub enum Device {
+    Block(Arc<dyn Block>),
+    // Devices without a common interface for their class yet
+    #[allow(dead_code)]
+    Other(DeviceClass, Arc<dyn Resource>)
+}
+
+#[derive(Clone)]
+pub struct DeviceInfo {
+    pub name: String,
+    // Device this one is part of, like the disk of a partition
+    pub parent: Option<String>,
+    pub device: Device
+}
+
+#[derive(Clone)]
+pub enum DeviceEvent {
+    Added(DeviceInfo),
+    Removed(DeviceInfo)
+}
+
+struct Subscriber {
+    class: DeviceClass,
+    events: Mutex<VecDeque<DeviceEvent>>,
+    waker: AtomicWaker
+}
+
+/// Stream of devices of a class being added or removed, starting with the devices already present.
+pub struct Subscription {
+    subscriber: Arc<Subscriber>
+}
+
+/// All devices in the system, named uniquely per class.
+pub struct DeviceRegistry {
+    devices: RwLock<BTreeMap<(DeviceClass, String), DeviceInfo>>,
+    subscribers: Mutex<Vec<Weak<Subscriber>>>
+}
+
+impl Device {
+    pub fn class(&self) -> DeviceClass {
+        match self {
+            Device::Block(_) => DeviceClass::Block,
+            Device::Other(class, _) => *class
+        }
+    }
+}
+
+impl DeviceInfo {
+    pub fn class(&self) -> DeviceClass {
+        self.device.class()
+    }
+
+    pub fn block(&self) -> Option<Arc<dyn Block>> {
+        match &self.device {
+            Device::Block(block) => Some(block.clone()),
+            Device::Other(_, _) => None
+        }
+    }
+}
+
+impl DeviceRegistry {
+    pub fn new() -> Self {
+        Self {
+            devices: RwLock::new(BTreeMap::new()),
+            subscribers: Mutex::new(Vec::new())
+        }
+    }
+
+    pub fn add(&self, name: &str, parent: Option<&str>, device: Device) -> Result<(), &'static str> {
+        let mut devices = self.devices.write();
+        let key = (device.class(), name.to_string());
+        if devices.contains_key(&key) {
+            return Err("Device name already in use");
+        }
+
+        let info = DeviceInfo {
+            name: name.to_string(),
+            parent: parent.map(str::to_string),
+            device
+        };
+        devices.insert(key, info.clone());
+
+        // Notify while holding the lock, so subscribers see events in order
+        self.notify(DeviceEvent::Added(info));
+        Ok(())
+    }
+
+    /// Remove the device and all devices that are part of it.
+    pub fn remove(&self, class: DeviceClass, name: &str) -> Result<(), &'static str> {
+        let children: Vec<String> = self.devices.read().values()
+            .filter(|child| child.class() == class && child.parent.as_deref() == Some(name))
+            .map(|child| child.name.clone())
+            .collect();
+        for child in children {
+            self.remove(class, &child)?;
+        }
+
+        let mut devices = self.devices.write();
+        let info = devices.remove(&(class, name.to_string())).ok_or("Device not found")?;
+        self.notify(DeviceEvent::Removed(info));
+        Ok(())
+    }
+
+    pub fn get(&self, class: DeviceClass, name: &str) -> Option<DeviceInfo> {
+        self.devices.read().get(&(class, name.to_string())).cloned()
+    }
+
+    pub fn block(&self, name: &str) -> Option<Arc<dyn Block>> {
+        self.get(DeviceClass::Block, name)?.block()
+    }
+
+    /// Devices ordered by class and name, optionally only of a single class.
+    pub fn list(&self, class: Option<DeviceClass>) -> Vec<DeviceInfo> {
+        self.devices.read().values().filter(|info| class.map_or(true, |class| info.class() == class)).cloned().collect()
+    }
+
+    pub fn subscribe(&self, class: DeviceClass) -> Subscription {
+        let devices = self.devices.read();
+        let events = devices.values().filter(|info| info.class() == class).cloned().map(DeviceEvent::Added).collect();
+        let subscriber = Arc::new(Subscriber {
+            class,
+            events: Mutex::new(events),
+            waker: AtomicWaker::new()
+        });
+
+        self.subscribers.lock().push(Arc::downgrade(&subscriber));
+        Subscription {
+            subscriber
+        }
+    }
+
+    fn notify(&self, event: DeviceEvent) {
+        let class = match &event {
+            DeviceEvent::Added(info) | DeviceEvent::Removed(info) => info.class()
+        };
+
+        let mut subscribers = self.subscribers.lock();
+        subscribers.retain(|subscriber| subscriber.strong_count() > 0);
+        for subscriber in subscribers.iter().filter_map(Weak::upgrade).filter(|subscriber| subscriber.class == class) {
+            subscriber.events.lock().push_back(event.clone());
+            subscriber.waker.wake();
+        }
+    }
+}
+
+impl Stream for Subscription {
+    type Item = DeviceEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<DeviceEvent>> {
+        self.subscriber.waker.register(cx.waker());
+        match self.subscriber.events.lock().pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => Poll::Pending
+        }
+    }
+}
+
+impl fmt::Display for DeviceClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DeviceClass::Block => "block",
+            DeviceClass::Char => "char",
+            DeviceClass::Net => "net",
+            DeviceClass::Display => "display",
+            DeviceClass::Input => "input"
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for DeviceClass {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(DeviceClass::Block),
+            "char" => Ok(DeviceClass::Char),
+            "net" => Ok(DeviceClass::Net),
+            "display" => Ok(DeviceClass::Display),
+            "input" => Ok(DeviceClass::Input),
+            _ => Err("Unknown device class")
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use spin::Mutex;
+
+use x86_64::instructions::port::Port;
+
+use crate::time::DateTime;
+
+const CMOS_ADDRESS_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+// Keep NMI disabled while selecting registers
+const CMOS_NMI_DISABLE: u8 = 1 << 7;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_CENTURY: u8 = 0x32;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+const RTC_STATUS_C: u8 = 0x0C;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_UPDATE_INTERRUPT: u8 = 1 << 4;
+const STATUS_C_UPDATE_ENDED: u8 = 1 << 4;
+
+const HOUR_PM: u8 = 1 << 7;
+
+const MAX_SPINS: usize = 100000;
+
+struct Cmos {
+    address_port: Port<u8>,
+    data_port: Port<u8>,
+}
+
+#[derive(PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8
+}
+
+impl Cmos {
+    pub fn new() -> Self {
+        Self {
+            address_port: Port::new(CMOS_ADDRESS_PORT),
+            data_port: Port::new(CMOS_DATA_PORT),
+        }
+    }
+
+    pub fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.address_port.write(CMOS_NMI_DISABLE | register);
+            self.data_port.read()
+        }
+    }
+
+    pub fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.address_port.write(CMOS_NMI_DISABLE | register);
+            self.data_port.write(value);
+        }
+    }
+
+    fn read_raw(&mut self) -> RawTime {
+        RawTime {
+            second: self.read(RTC_SECONDS),
+            minute: self.read(RTC_MINUTES),
+            hour: self.read(RTC_HOURS),
+            day: self.read(RTC_DAY),
+            month: self.read(RTC_MONTH),
+            year: self.read(RTC_YEAR),
+            century: self.read(RTC_CENTURY)
+        }
+    }
+
+    fn update_in_progress(&mut self) -> bool {
+        self.read(RTC_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+    }
+}
+
+pub struct CmosRtc {
+    cmos: Mutex<Cmos>
+}
+
+impl CmosRtc {
+    pub fn new() -> Self {
+        Self {
+            cmos: Mutex::new(Cmos::new())
+        }
+    }
+
+    pub fn read_time(&self) -> Option<DateTime> {
+        let mut cmos = self.cmos.lock();
+
+        // Read until two consecutive reads outside of an update agree
+        let mut spin_count = 0;
+        let raw = loop {
+            while cmos.update_in_progress() {
+                spin_count += 1;
+                if spin_count == MAX_SPINS {
+                    return None;
+                }
+            }
+
+            let first = cmos.read_raw();
+            if !cmos.update_in_progress() && first == cmos.read_raw() {
+                break first;
+            }
+        };
+
+        let status = cmos.read(RTC_STATUS_B);
+        decode(&raw, status)
+    }
+
+    pub fn enable_update_interrupt(&self) {
+        let mut cmos = self.cmos.lock();
+        let status = cmos.read(RTC_STATUS_B);
+        cmos.write(RTC_STATUS_B, status | STATUS_B_UPDATE_INTERRUPT);
+
+        // Clear pending interrupts, otherwise no new ones are raised
+        cmos.read(RTC_STATUS_C);
+    }
+
+    /// Acknowledge the interrupt and return the time if a new second just started.
+    pub fn handle_interrupt(&self) -> Option<DateTime> {
+        let mut cmos = self.cmos.lock();
+        if cmos.read(RTC_STATUS_C) & STATUS_C_UPDATE_ENDED == 0 {
+            return None;
+        }
+
+        // Registers are stable for almost a second after the update ended
+        let raw = cmos.read_raw();
+        let status = cmos.read(RTC_STATUS_B);
+        decode(&raw, status)
+    }
+}
+
+fn decode(raw: &RawTime, status: u8) -> Option<DateTime> {
+    let binary = status & STATUS_B_BINARY != 0;
+    let convert = |value: u8| if binary { value } else { (value & 0x0F) + (value >> 4) * 10 };
+
+    let mut hour = convert(raw.hour & !HOUR_PM);
+    if status & STATUS_B_24_HOUR == 0 {
+        hour %= 12;
+        if raw.hour & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+
+    let century = match convert(raw.century) {
+        century @ 19..=99 => century as u16,
+        _ => 20
+    };
+
+    DateTime::new(century * 100 + convert(raw.year) as u16, convert(raw.month), convert(raw.day), hour, convert(raw.minute), convert(raw.second))
+}
@@ -87,6 +87,12 @@ impl Console {
         }
     }
 
+    pub fn clear(&mut self) {
+        self.chars.fill(ScreenChar { character: b' ' });
+        self.pos = 0;
+        self.draw_text(0, self.chars.len());
+    }
+
     pub fn scroll(&mut self) {
         unsafe {
             let row_size = self.fb.stride * (self.fb.bpp / 8) * self.font_height;
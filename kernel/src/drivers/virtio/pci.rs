@@ -209,6 +209,29 @@ impl<S> VirtioPciDevice<S> {
         dev_feat
     }
 
+    pub fn get_device_status(&self) -> u8 {
+        Arch::memory_barrier();
+        self.common.device_status
+    }
+
+    /// Select the features the driver will use, a subset of `get_features`.
+    pub fn set_features(&mut self, features: u64) {
+        Arch::memory_barrier();
+        self.common.driver_feature_select = 1;
+        Arch::memory_barrier();
+        self.common.driver_feature = (features >> 32) as u32;
+
+        Arch::memory_barrier();
+        self.common.driver_feature_select = 0;
+        Arch::memory_barrier();
+        self.common.driver_feature = features as u32;
+    }
+
+    pub fn device_config(&self) -> &S {
+        Arch::memory_barrier();
+        self.device_cfg
+    }
+
     pub fn get_virtq_handler(&mut self, index: u16) -> Option<VirtqHandler<'_>> {
         self.common.queue_select = index;
         let notify_addr = unsafe { self.notify_cfg.memory.as_ptr().byte_add(self.common.queue_notify_off as usize * self.notify_cfg.notify_off_multiplier as usize) };
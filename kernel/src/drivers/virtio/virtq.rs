@@ -1,21 +1,24 @@
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 
+use core::any::Any;
 use core::future::poll_fn;
 use core::intrinsics::unaligned_volatile_store;
+use core::mem;
+use core::ptr;
 use core::sync::atomic::{fence, Ordering};
-use core::task::{Poll, Context};
+use core::task::{Poll, Waker};
 
-use futures_util::future::BoxFuture;
-use futures_util::task::AtomicWaker;
+use spin::Mutex;
 
-use crate::arch::KERNEL_ADDRESS_BASE;
+use crate::arch::{Arch, KERNEL_ADDRESS_BASE};
+use crate::arch::system::System;
+use crate::runtime::runtime;
 
 use super::pci::ComCfgRaw;
 
 pub const VIRTQ_DESC_F_NEXT: u16 = 1 << 0;
 pub const VIRTQ_DESC_F_WRITE: u16 = 1 << 1;
-pub const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
-pub const VIRTQ_DESC_F_USED: u16 = 1 << 15;
 
 #[repr(C, align(16))]
 #[derive(Default, Debug, Copy, Clone)]
@@ -51,18 +54,30 @@ struct UsedRing<const COUNT: usize> {
 	event: u16,
 }
 
+// Memory the device accesses through a chain, kept alive until the device returns it
+type Buffers = Box<dyn Any + Send>;
+
+// State of the chain starting at a descriptor
+enum Chain {
+    Free,
+    Pending(Option<Waker>, Buffers),
+    Done(u32, Buffers),
+    // Request was dropped, free the descriptors and buffers once the device is done with them.
+    // The buffers are only held to keep them alive.
+    Abandoned(#[allow(dead_code)] Buffers)
+}
+
 struct StaticQueue<const COUNT: usize> {
-    avail_wrap_count: bool,
-    state: [bool; COUNT],
-    descr_head: usize,
-    descr_current: usize,
-    descr_next: usize,
-    avail_next: usize,
+    index: u16,
     notify_addr: *mut u16,
-    wakers: [AtomicWaker; COUNT],
+    free_head: u16,
+    num_free: usize,
+    used_last: u16,
+    chains: [Chain; COUNT],
+    // Tasks waiting for free descriptors
+    space: Vec<Waker>,
     descriptors: DescriptorRing<COUNT>,
     avail: AvailRing<COUNT>,
-    used_last: usize,
     used: UsedRing<COUNT>,
 }
 
@@ -75,7 +90,14 @@ unsafe impl<const COUNT: usize> Send for StaticQueue<COUNT> {}
 unsafe impl<const COUNT: usize> Sync for StaticQueue<COUNT> {}
 
 pub struct Virtq {
-    queues: Box<dyn Queue>
+    queue: Mutex<Box<dyn Queue>>
+}
+
+// Hands the chain over to the interrupt handler when a request is dropped before completion
+struct InFlight<'a> {
+    virtq: &'a Virtq,
+    head: u16,
+    done: bool
 }
 
 pub struct VirtqHandler<'a> {
@@ -86,7 +108,12 @@ pub struct VirtqHandler<'a> {
 
 trait Queue: Send + Sync {
     fn get_addresses(&self) -> (u64, u64, u64);
-    fn request<'a>(&'a mut self, desc: &'a [Descriptor]) -> BoxFuture<'a, u16>;
+    fn size(&self) -> usize;
+    /// Make a chain owning `buffers` available to the device, or wake `waker` once enough descriptors are free.
+    fn submit(&mut self, descs: &[Descriptor], buffers: &mut Option<Buffers>, waker: &Waker) -> Option<u16>;
+    /// Return the number of bytes written by the device and the buffers once the chain completed.
+    fn poll_chain(&mut self, head: u16, waker: &Waker) -> Poll<(u32, Buffers)>;
+    fn abandon(&mut self, head: u16);
     fn process(&mut self);
 }
 
@@ -139,18 +166,16 @@ impl<'a> VirtqHandler<'a> {
 }
 
 impl Descriptor {
+    /// Descriptor for a small kernel object, which must not cross a page boundary.
     pub fn new<T>(data: &mut T, flags: u16) -> Self {
-        Self {
-            address: data as *mut T as u64,
-            len: core::mem::size_of::<T>() as u32,
-            flags,
-            next: 0
-        }
+        let address = runtime().system.translate(data as *mut T as usize).expect("Descriptor not mapped");
+        Self::new_raw(address as u64, core::mem::size_of::<T>(), flags)
     }
 
-    pub fn new_raw<T>(data: *mut T, len: usize, flags: u16) -> Self {
+    /// Descriptor for `len` bytes at physical `address`.
+    pub fn new_raw(address: u64, len: usize, flags: u16) -> Self {
         Self {
-            address: data as u64,
+            address,
             len: len as u32,
             flags,
             next: 0
@@ -159,16 +184,15 @@ impl Descriptor {
 }
 
 impl<const COUNT: usize> StaticQueue<COUNT> {
-    pub fn new(notify_addr: *mut u16) -> Self {
-        let mut queues = Self {
-            avail_wrap_count: true,
-            state: [false; COUNT],
-            descr_head: 0,
-            descr_current: 0,
-            descr_next: 0,
-            avail_next: 0,
+    pub fn new(index: u16, notify_addr: *mut u16) -> Self {
+        let mut queue = Self {
+            index,
             notify_addr,
-            wakers: core::array::from_fn(|_| AtomicWaker::new()),
+            free_head: 0,
+            num_free: COUNT,
+            used_last: 0,
+            chains: core::array::from_fn(|_| Chain::Free),
+            space: Vec::new(),
             descriptors: [Descriptor::default(); COUNT],
             avail: AvailRing {
                 flags: 0,
@@ -176,7 +200,6 @@ impl<const COUNT: usize> StaticQueue<COUNT> {
                 ring: [0; COUNT],
                 event: 0,
             },
-            used_last: 0,
             used: UsedRing {
                 flags: 0,
                 index: 0,
@@ -184,72 +207,34 @@ impl<const COUNT: usize> StaticQueue<COUNT> {
                 event: 0,
             }
         };
-        for i in 0..COUNT {
-            queues.descriptors[i].next = (i + 1) as u16;
-        }
-        queues
-    }
 
-    pub fn insert_descriptor(&mut self, desc: &Descriptor) {
-        // Track next available free descriptor
-        let next_free = self.descriptors[self.descr_next].next as usize;
-
-        self.descriptors[self.descr_next] = *desc;
-        self.descriptors[self.descr_next].address -= KERNEL_ADDRESS_BASE as u64;
-        if self.avail_wrap_count {
-            self.descriptors[self.descr_next].flags |= VIRTQ_DESC_F_AVAIL;
-            self.descriptors[self.descr_next].flags &= !VIRTQ_DESC_F_USED;
-        } else {
-            self.descriptors[self.descr_next].flags &= !VIRTQ_DESC_F_AVAIL;
-            self.descriptors[self.descr_next].flags |= VIRTQ_DESC_F_USED;
-        }
-
-        if self.descr_current != self.descr_next {
-            // Update previous descriptor to chain descriptors
-            self.descriptors[self.descr_current].flags |= VIRTQ_DESC_F_NEXT;
-            self.descriptors[self.descr_current].next = self.descr_next as u16;
-            self.descr_current = self.descr_next;
+        // Link all descriptors in the free list
+        for i in 0..COUNT - 1 {
+            queue.descriptors[i].next = (i + 1) as u16;
         }
-
-        self.descr_next = next_free;
+        queue
     }
 
-    pub fn process_used_queue(&mut self, ignore: usize) {
-        while self.used_last != self.used.index as usize {
-            // Notify other sleeping pollers
-            let idx = self.used.ring[self.used_last].id as usize;
-            self.state[idx] = true;
-
-            if ignore != idx {
-                self.wakers[idx].wake();
-            }
-
-            // Update counter
-            self.used_last = (self.used_last + 1) % self.descriptors.len();
-            if self.used_last == 0 {
-                self.avail_wrap_count = !self.avail_wrap_count;
-            }
+    fn free_chain(&mut self, head: u16) {
+        let mut last = head as usize;
+        let mut count = 1;
+        while self.descriptors[last].flags & VIRTQ_DESC_F_NEXT != 0 {
+            last = self.descriptors[last].next as usize;
+            count += 1;
         }
-    }
 
-    pub fn poll_request(&mut self, ctx: &mut Context, head: usize) -> Poll<()> {
-        if self.state[head] {
-            return Poll::Ready(())
-        }
+        self.descriptors[last].next = self.free_head;
+        self.free_head = head;
+        self.num_free += count;
+        self.chains[head as usize] = Chain::Free;
 
-        self.wakers[head].register(&ctx.waker());
-        if self.state[head] {
-            Poll::Ready(())
-        } else {
-            self.wakers[head].wake();
-            Poll::Pending
-        }
+        self.space.drain(..).for_each(Waker::wake);
     }
 
-    pub fn notify(&self, val: u16) {
+    pub fn notify(&self) {
         fence(Ordering::Acquire);
 
-        unsafe { unaligned_volatile_store(self.notify_addr, val); }
+        unsafe { unaligned_volatile_store(self.notify_addr, self.index); }
 
         fence(Ordering::Release);
     }
@@ -260,35 +245,89 @@ impl<const COUNT: usize> Queue for StaticQueue<COUNT> {
         (self.descriptors.as_ptr() as u64, &self.avail as *const AvailRing<COUNT> as u64, &self.used as *const UsedRing<COUNT> as u64)
     }
 
-    fn request<'a>(&'a mut self, desc: &'a [Descriptor]) -> BoxFuture<'a, u16> {
-        Box::pin(async {
-            desc.iter().for_each(|d| self.insert_descriptor(d));
-            self.avail.ring[self.avail_next] = self.descr_head as u16;
-            self.avail_next += 1;
+    fn size(&self) -> usize {
+        COUNT
+    }
+
+    fn submit(&mut self, descs: &[Descriptor], buffers: &mut Option<Buffers>, waker: &Waker) -> Option<u16> {
+        if self.num_free < descs.len() {
+            self.space.push(waker.clone());
+            return None;
+        }
+
+        // Take descriptors from the free list, their next fields already link them together
+        let head = self.free_head;
+        let mut last = head;
+        let mut next = head;
+        for desc in descs {
+            last = next;
+            next = self.descriptors[last as usize].next;
+            self.descriptors[last as usize] = Descriptor {
+                flags: desc.flags | VIRTQ_DESC_F_NEXT,
+                next,
+                ..*desc
+            };
+        }
+        self.descriptors[last as usize].flags &= !VIRTQ_DESC_F_NEXT;
+        self.free_head = next;
+        self.num_free -= descs.len();
+        self.chains[head as usize] = Chain::Pending(None, buffers.take().expect("Chain submitted twice"));
+
+        self.avail.ring[self.avail.index as usize % COUNT] = head;
+
+        // Write barrier so that device sees changes to descriptor table and available ring
+        fence(Ordering::SeqCst);
 
-            // Write barrier so that device sees changes to descriptor table and available ring
-            fence(Ordering::SeqCst);
+        unsafe { ptr::write_volatile(&mut self.avail.index, self.avail.index.wrapping_add(1)) };
 
-            self.avail.index += 1;
+        // Write barrier so that device can see change to available index before the notification
+        fence(Ordering::SeqCst);
 
-            // Write barrier so that device can see change to available index after this method returns.
-            fence(Ordering::SeqCst);
+        self.notify();
+        Some(head)
+    }
 
-            // Start new chain
-            let current_chain = self.descr_head;
-            self.descr_head = self.descr_next;
-            self.descr_current = self.descr_next;
+    fn poll_chain(&mut self, head: u16, waker: &Waker) -> Poll<(u32, Buffers)> {
+        if let Chain::Pending(pending, _) = &mut self.chains[head as usize] {
+            *pending = Some(waker.clone());
+            return Poll::Pending;
+        }
 
-            // Notify device about new descriptor chain
-            self.notify(current_chain as u16);
+        match mem::replace(&mut self.chains[head as usize], Chain::Free) {
+            Chain::Done(len, buffers) => {
+                self.free_chain(head);
+                Poll::Ready((len, buffers))
+            },
+            Chain::Free | Chain::Pending(..) | Chain::Abandoned(_) => unreachable!()
+        }
+    }
 
-            poll_fn(|ctx| self.poll_request(ctx, current_chain)).await;
-            current_chain as u16
-        })
+    fn abandon(&mut self, head: u16) {
+        match mem::replace(&mut self.chains[head as usize], Chain::Free) {
+            Chain::Done(..) => self.free_chain(head),
+            Chain::Pending(_, buffers) => self.chains[head as usize] = Chain::Abandoned(buffers),
+            Chain::Free | Chain::Abandoned(_) => unreachable!()
+        }
     }
 
     fn process(&mut self) {
-        self.process_used_queue(usize::MAX);
+        // Written by the device
+        while self.used_last != unsafe { ptr::read_volatile(&self.used.index) } {
+            fence(Ordering::Acquire);
+
+            let elem = self.used.ring[self.used_last as usize % COUNT];
+            self.used_last = self.used_last.wrapping_add(1);
+
+            let head = elem.id as u16;
+            match mem::replace(&mut self.chains[head as usize], Chain::Free) {
+                Chain::Pending(waker, buffers) => {
+                    self.chains[head as usize] = Chain::Done(elem.len, buffers);
+                    waker.into_iter().for_each(Waker::wake);
+                },
+                Chain::Abandoned(_) => self.free_chain(head),
+                chain => self.chains[head as usize] = chain
+            }
+        }
     }
 }
 
@@ -297,11 +336,11 @@ impl Virtq {
         let size = handler.set_vq_size(256);
 
         let queues: Box<dyn Queue> = if size == 128 {
-            Box::new(Queue128::new(handler.get_notify_addr()))
+            Box::new(Queue128::new(handler.index, handler.get_notify_addr()))
         } else if size == 256 {
-            Box::new(Queue256::new(handler.get_notify_addr()))
+            Box::new(Queue256::new(handler.index, handler.get_notify_addr()))
         } else if size == 512 {
-            Box::new(Queue512::new(handler.get_notify_addr()))
+            Box::new(Queue512::new(handler.index, handler.get_notify_addr()))
         } else {
             panic!("Invalid queue size!");
         };
@@ -314,17 +353,54 @@ impl Virtq {
         handler.enable_queue();
 
         Self {
-            queues
+            queue: Mutex::new(queues)
         }
     }
 
-    pub async fn request(&mut self, descs: &[Descriptor]) -> Result<(), &'static str> {
-        self.queues.request(descs).await;
+    /// Number of descriptors, which also limits the length of a chain.
+    pub fn size(&self) -> usize {
+        self.lock(|queue| queue.size())
+    }
+
+    /// Submit a descriptor chain and wait for the device to complete it.
+    /// The descriptors may only point into `buffers`, which the queue keeps until the device is done,
+    /// even when the request is dropped. Returns the number of bytes written by the device and the buffers.
+    pub async fn request<T: Send + 'static>(&self, descs: &[Descriptor], buffers: Box<T>) -> Result<(u32, Box<T>), &'static str> {
+        if descs.is_empty() || descs.len() > self.size() {
+            return Err("Invalid descriptor chain length");
+        }
+
+        let mut buffers: Option<Buffers> = Some(buffers);
+        let head = poll_fn(|ctx| match self.lock(|queue| queue.submit(descs, &mut buffers, ctx.waker())) {
+            Some(head) => Poll::Ready(head),
+            None => Poll::Pending
+        }).await;
+
+        let mut request = InFlight {
+            virtq: self,
+            head,
+            done: false
+        };
+        let (len, buffers) = poll_fn(|ctx| self.lock(|queue| queue.poll_chain(head, ctx.waker()))).await;
+        request.done = true;
 
-        Ok(())
+        Ok((len, buffers.downcast().unwrap()))
     }
 
-    pub fn process(&mut self) {
-        self.queues.process();
+    /// Complete chains used by the device, called from the interrupt handler.
+    pub fn process(&self) {
+        self.lock(|queue| queue.process());
+    }
+
+    fn lock<R>(&self, f: impl FnOnce(&mut dyn Queue) -> R) -> R {
+        Arch::without_interrupts(|| f(self.queue.lock().as_mut()))
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.virtq.lock(|queue| queue.abandon(self.head));
+        }
     }
 }
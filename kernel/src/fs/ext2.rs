@@ -0,0 +1,502 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use async_trait::async_trait;
+
+use core::fmt::Write;
+use core::mem::size_of;
+use core::time::Duration;
+
+use crate::block::{Block, SECTOR_SIZE};
+use crate::runtime::runtime;
+use crate::time::DateTime;
+
+use super::{Directory, Error, File, FileEntry, FileSystem, FileType, Metadata, Result, SeekFrom, seek_position};
+
+/// Start of the superblock, which is located at the same offset for every block size.
+pub const SUPERBLOCK_OFFSET: usize = 1024;
+
+// Leading part of the superblock, up to the fields used by the driver
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+struct Superblock {
+    inodes_count: u32,
+    blocks_count: u32,
+    reserved_blocks_count: u32,
+    free_blocks_count: u32,
+    free_inodes_count: u32,
+    first_data_block: u32,
+    log_block_size: u32,
+    log_frag_size: u32,
+    blocks_per_group: u32,
+    frags_per_group: u32,
+    inodes_per_group: u32,
+    mount_time: u32,
+    write_time: u32,
+    mount_count: u16,
+    max_mount_count: u16,
+    magic: u16,
+    state: u16,
+    errors: u16,
+    minor_rev_level: u16,
+    last_check: u32,
+    check_interval: u32,
+    creator_os: u32,
+    rev_level: u32,
+    default_resuid: u16,
+    default_resgid: u16,
+    // Only valid from revision 1
+    first_inode: u32,
+    inode_size: u16,
+    block_group_nr: u16,
+    feature_compat: u32,
+    feature_incompat: u32,
+    feature_ro_compat: u32
+}
+
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+struct GroupDescriptor {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks_count: u16,
+    free_inodes_count: u16,
+    used_dirs_count: u16,
+    pad: u16,
+    reserved: [u8; 12]
+}
+
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+struct Inode {
+    mode: u16,
+    uid: u16,
+    size: u32,
+    access_time: u32,
+    change_time: u32,
+    modification_time: u32,
+    deletion_time: u32,
+    gid: u16,
+    links_count: u16,
+    // Counted in 512 byte units, whatever the block size
+    blocks: u32,
+    flags: u32,
+    osd1: u32,
+    block: [u32; 15],
+    generation: u32,
+    file_acl: u32,
+    // Upper half of the size of regular files with the large file feature
+    size_high: u32,
+    fragment_address: u32,
+    osd2: [u8; 12]
+}
+
+// Header of a directory entry, followed by the name
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+struct DirEntryHeader {
+    inode: u32,
+    record_length: u16,
+    name_length: u8,
+    // Only used with the file type feature, the upper byte of the name length otherwise
+    file_type: u8
+}
+
+const EXT2_MAGIC: u16 = 0xEF53;
+const STATE_CLEAN: u16 = 1;
+const ROOT_INODE: u32 = 2;
+const GOOD_OLD_INODE_SIZE: u16 = 128;
+
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+const MODE_TYPE_MASK: u16 = 0xF000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_REGULAR: u16 = 0x8000;
+const MODE_SYMLINK: u16 = 0xA000;
+const MODE_PERMISSIONS: u16 = 0o7777;
+
+const DIRECT_BLOCKS: usize = 12;
+const INDIRECT_BLOCK: usize = 12;
+// Symlinks with short targets keep them in the block pointers
+const FAST_SYMLINK_LENGTH: u64 = 60;
+const MAX_SYMLINKS: usize = 8;
+
+pub struct Ext2 {
+    block: Arc<dyn Block>,
+    block_size: usize,
+    inodes_per_group: u32,
+    inode_size: usize,
+    inodes_count: u32,
+    free_blocks: u64,
+    large_file: bool,
+    groups: Vec<GroupDescriptor>
+}
+
+struct Ext2File {
+    fs: Arc<Ext2>,
+    inode: Inode,
+    position: u64
+}
+
+struct Ext2Directory {
+    entries: vec::IntoIter<FileEntry>
+}
+
+impl Superblock {
+    fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < size_of::<Superblock>() {
+            return None;
+        }
+
+        let superblock = unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const Superblock) };
+        match superblock.magic == EXT2_MAGIC {
+            true => Some(superblock),
+            false => None
+        }
+    }
+}
+
+impl Inode {
+    fn file_type(&self) -> FileType {
+        match self.mode & MODE_TYPE_MASK {
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_SYMLINK => FileType::Symlink,
+            _ => FileType::File
+        }
+    }
+
+    fn metadata(&self, fs: &Ext2) -> Metadata {
+        let time = |seconds: u32| match seconds {
+            0 => None,
+            seconds => Some(DateTime::from_unix(Duration::from_secs(seconds as u64)))
+        };
+
+        Metadata {
+            file_type: self.file_type(),
+            size: self.size(fs),
+            mode: self.mode & MODE_PERMISSIONS,
+            // The change time is not the creation time, which ext2 doesn't record
+            created: None,
+            modified: time(self.modification_time),
+            accessed: time(self.access_time)
+        }
+    }
+
+    fn size(&self, fs: &Ext2) -> u64 {
+        match fs.large_file && self.mode & MODE_TYPE_MASK == MODE_REGULAR {
+            true => (self.size_high as u64) << 32 | self.size as u64,
+            false => self.size as u64
+        }
+    }
+}
+
+impl Ext2 {
+    /// Whether the buffer, starting at `SUPERBLOCK_OFFSET`, holds an ext2 superblock.
+    pub fn probe(buf: &[u8]) -> bool {
+        Superblock::parse(buf).is_some()
+    }
+
+    pub async fn new(block: Arc<dyn Block>) -> Result<Self> {
+        let mut buf = [0u8; 1024];
+        block.read(&mut buf, (SUPERBLOCK_OFFSET / SECTOR_SIZE) as u64).await?;
+        let superblock = Superblock::parse(&buf).ok_or(Error::Corrupted("Missing ext2 superblock"))?;
+
+        if superblock.rev_level > 0 && superblock.feature_incompat & !INCOMPAT_FILETYPE != 0 {
+            return Err(Error::Corrupted("Unsupported ext2 features"));
+        }
+
+        let block_size = 1024usize.checked_shl(superblock.log_block_size).filter(|size| *size <= 65536).ok_or(Error::Corrupted("Invalid block size"))?;
+        let inode_size = match superblock.rev_level {
+            0 => GOOD_OLD_INODE_SIZE,
+            _ => superblock.inode_size
+        } as usize;
+        if inode_size < GOOD_OLD_INODE_SIZE as usize || !inode_size.is_power_of_two() || inode_size > block_size {
+            return Err(Error::Corrupted("Invalid inode size"));
+        }
+        if superblock.blocks_per_group == 0 || superblock.inodes_per_group == 0 || superblock.blocks_count <= superblock.first_data_block {
+            return Err(Error::Corrupted("Invalid block group size"));
+        }
+
+        let sectors_per_block = (block_size / SECTOR_SIZE) as u64;
+        if superblock.blocks_count as u64 * sectors_per_block > block.sector_count() {
+            return Err(Error::Corrupted("Filesystem larger than device"));
+        }
+
+        // The descriptors follow the block holding the superblock
+        let group_count = (superblock.blocks_count - superblock.first_data_block + superblock.blocks_per_group - 1) / superblock.blocks_per_group;
+        let table_size = group_count as usize * size_of::<GroupDescriptor>();
+        let mut table = vec![0u8; (table_size + block_size - 1) / block_size * block_size];
+        block.read(&mut table, (superblock.first_data_block as u64 + 1) * sectors_per_block).await?;
+        let groups = table.chunks_exact(size_of::<GroupDescriptor>())
+            .take(group_count as usize)
+            .map(|raw| unsafe { core::ptr::read_unaligned(raw.as_ptr() as *const GroupDescriptor) })
+            .collect();
+
+        if superblock.state != STATE_CLEAN {
+            writeln!(runtime().console.lock(), "ext2 filesystem was not cleanly unmounted").unwrap();
+        }
+
+        Ok(Self {
+            block,
+            block_size,
+            inodes_per_group: superblock.inodes_per_group,
+            inode_size,
+            inodes_count: superblock.inodes_count,
+            free_blocks: superblock.free_blocks_count as u64,
+            large_file: superblock.rev_level > 0 && superblock.feature_ro_compat & RO_COMPAT_LARGE_FILE != 0,
+            groups
+        })
+    }
+
+    fn block_sector(&self, block: u32) -> u64 {
+        block as u64 * (self.block_size / SECTOR_SIZE) as u64
+    }
+
+    async fn read_inode(&self, number: u32) -> Result<Inode> {
+        if number == 0 || number > self.inodes_count {
+            return Err(Error::Corrupted("Invalid inode number"));
+        }
+
+        let index = number - 1;
+        let group = self.groups.get((index / self.inodes_per_group) as usize).ok_or(Error::Corrupted("Inode outside of block groups"))?;
+        let offset = (index % self.inodes_per_group) as u64 * self.inode_size as u64;
+
+        let mut buf = [0u8; SECTOR_SIZE];
+        self.block.read(&mut buf, self.block_sector(group.inode_table) + offset / SECTOR_SIZE as u64).await?;
+        let start = offset as usize % SECTOR_SIZE;
+        Ok(unsafe { core::ptr::read_unaligned(buf[start..].as_ptr() as *const Inode) })
+    }
+
+    // Entry of an indirect block
+    async fn read_pointer(&self, block: u32, index: usize) -> Result<u32> {
+        let offset = index * size_of::<u32>();
+        let mut buf = [0u8; SECTOR_SIZE];
+        self.block.read(&mut buf, self.block_sector(block) + (offset / SECTOR_SIZE) as u64).await?;
+        let start = offset % SECTOR_SIZE;
+        Ok(u32::from_le_bytes(buf[start..start + 4].try_into().unwrap()))
+    }
+
+    /// Block holding the block of the file at `index`, zero for a hole.
+    async fn map_block(&self, inode: &Inode, index: u64) -> Result<u32> {
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok(inode.block[index as usize]);
+        }
+
+        // Walk single, double and triple indirect blocks, each covering a power of the pointers per block
+        let per_block = (self.block_size / size_of::<u32>()) as u64;
+        let mut index = index - DIRECT_BLOCKS as u64;
+        let mut span = per_block;
+        for depth in 0..3 {
+            if index < span {
+                let mut block = inode.block[INDIRECT_BLOCK + depth];
+                while span > 1 && block != 0 {
+                    span /= per_block;
+                    block = self.read_pointer(block, (index / span) as usize).await?;
+                    index %= span;
+                }
+                return Ok(block);
+            }
+            index -= span;
+            span *= per_block;
+        }
+        Err(Error::FileTooLarge)
+    }
+
+    async fn read_data(&self, inode: &Inode, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let len = buf.len().min(inode.size(self).saturating_sub(offset) as usize);
+        let mut block_buf = vec![0u8; self.block_size];
+
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let block_offset = (position % self.block_size as u64) as usize;
+            let count = (len - done).min(self.block_size - block_offset);
+
+            match self.map_block(inode, position / self.block_size as u64).await? {
+                // Holes in sparse files read as zeroes
+                0 => buf[done..done + count].fill(0),
+                block if count == self.block_size => self.block.read(&mut buf[done..done + count], self.block_sector(block)).await?,
+                block => {
+                    self.block.read(&mut block_buf, self.block_sector(block)).await?;
+                    buf[done..done + count].copy_from_slice(&block_buf[block_offset..block_offset + count]);
+                }
+            }
+            done += count;
+        }
+        Ok(done)
+    }
+
+    /// Names and inode numbers in a directory, including `.` and `..`.
+    async fn read_dir(&self, inode: &Inode) -> Result<Vec<(String, u32)>> {
+        // Read a block at a time, as the size of a corrupted directory can't be trusted
+        let mut block_buf = vec![0u8; self.block_size];
+        let mut entries = Vec::new();
+        let mut position = 0;
+        while position < inode.size(self) {
+            let len = self.read_data(inode, &mut block_buf, position).await?;
+            if len == 0 {
+                break;
+            }
+            position += len as u64;
+
+            // Entries never cross a block boundary
+            let block = &block_buf[..len];
+            let mut offset = 0;
+            while offset + size_of::<DirEntryHeader>() <= block.len() {
+                let header = unsafe { core::ptr::read_unaligned(block[offset..].as_ptr() as *const DirEntryHeader) };
+                let record_length = header.record_length as usize;
+                let name_end = offset + size_of::<DirEntryHeader>() + header.name_length as usize;
+                if record_length < size_of::<DirEntryHeader>() || offset + record_length > block.len() || name_end > offset + record_length {
+                    return Err(Error::Corrupted("Invalid directory entry"));
+                }
+
+                // Unused entries have inode zero
+                if header.inode != 0 {
+                    let name = String::from_utf8_lossy(&block[offset + size_of::<DirEntryHeader>()..name_end]).to_string();
+                    entries.push((name, header.inode));
+                }
+                offset += record_length;
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn read_link(&self, inode: &Inode) -> Result<String> {
+        let size = inode.size(self);
+        let acl_blocks = if inode.file_acl != 0 { (self.block_size / SECTOR_SIZE) as u32 } else { 0 };
+        if size < FAST_SYMLINK_LENGTH && inode.blocks == acl_blocks {
+            let target: Vec<u8> = { inode.block }.iter().flat_map(|pointer| pointer.to_le_bytes()).take(size as usize).collect();
+            return Ok(String::from_utf8_lossy(&target).to_string());
+        }
+
+        let mut target = vec![0u8; size.min(self.block_size as u64) as usize];
+        let len = self.read_data(inode, &mut target, 0).await?;
+        Ok(String::from_utf8_lossy(&target[..len]).to_string())
+    }
+
+    /// Inode the path refers to with symlinks followed, absolute targets start at the root of this filesystem.
+    async fn lookup(&self, path: &str) -> Result<(u32, Inode)> {
+        let mut components: VecDeque<String> = path.split('/').filter(|name| !name.is_empty()).map(str::to_string).collect();
+        let mut current = (ROOT_INODE, self.read_inode(ROOT_INODE).await?);
+        let mut links = 0;
+
+        while let Some(name) = components.pop_front() {
+            if current.1.file_type() != FileType::Directory {
+                return Err(Error::NotADirectory);
+            }
+            if name == "." {
+                continue;
+            }
+
+            let (_, number) = self.read_dir(&current.1).await?.into_iter().find(|(entry, _)| *entry == name).ok_or(Error::NotFound)?;
+            let inode = self.read_inode(number).await?;
+            if inode.file_type() != FileType::Symlink {
+                current = (number, inode);
+                continue;
+            }
+
+            // Continue with the target, relative to the directory containing the link
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(Error::TooManyLinks);
+            }
+            let target = self.read_link(&inode).await?;
+            if target.starts_with('/') {
+                current = (ROOT_INODE, self.read_inode(ROOT_INODE).await?);
+            }
+            for component in target.split('/').filter(|name| !name.is_empty()).rev() {
+                components.push_front(component.to_string());
+            }
+        }
+        Ok(current)
+    }
+}
+
+#[async_trait]
+impl FileSystem for Ext2 {
+    async fn open(self: Arc<Self>, path: &str) -> Result<Box<dyn File>> {
+        let (_, inode) = self.lookup(path).await?;
+        match inode.mode & MODE_TYPE_MASK {
+            MODE_REGULAR => Ok(Box::new(Ext2File {
+                fs: self,
+                inode,
+                position: 0
+            })),
+            MODE_DIRECTORY => Err(Error::IsADirectory),
+            // Device nodes, pipes and sockets have no data to read
+            _ => Err(Error::InvalidArgument)
+        }
+    }
+
+    async fn open_dir(self: Arc<Self>, path: &str) -> Result<Box<dyn Directory>> {
+        let (_, inode) = self.lookup(path).await?;
+        if inode.file_type() != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+
+        // Entries are read at once, so changes after opening are not seen
+        let mut entries = Vec::new();
+        for (name, number) in self.read_dir(&inode).await? {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let metadata = self.read_inode(number).await?.metadata(&self);
+            entries.push(FileEntry {
+                inode: number as u64,
+                name,
+                metadata
+            });
+        }
+        Ok(Box::new(Ext2Directory {
+            entries: entries.into_iter()
+        }))
+    }
+
+    async fn stat(&self, path: &str) -> Result<Metadata> {
+        let (_, inode) = self.lookup(path).await?;
+        Ok(inode.metadata(self))
+    }
+
+    fn free_space(&self) -> Option<u64> {
+        Some(self.free_blocks * self.block_size as u64)
+    }
+}
+
+#[async_trait]
+impl File for Ext2File {
+    fn stat(&self) -> Metadata {
+        self.inode.metadata(&self.fs)
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.read_at(buf, self.position).await?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.position = seek_position(self.position, self.inode.size(&self.fs), pos)?;
+        Ok(self.position)
+    }
+
+    async fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        self.fs.read_data(&self.inode, buf, offset).await
+    }
+}
+
+#[async_trait]
+impl Directory for Ext2Directory {
+    async fn next_entry(&mut self) -> Result<Option<FileEntry>> {
+        Ok(self.entries.next())
+    }
+}
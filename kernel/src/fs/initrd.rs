@@ -0,0 +1,211 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use core::fmt::Write;
+use core::str;
+
+use spin::Once;
+
+use crate::runtime::runtime;
+
+use super::{Error, Result};
+
+const CPIO_MAGIC: &[u8] = b"07070";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const TAR_BLOCK_SIZE: usize = 512;
+const TAR_MAGIC_OFFSET: usize = 257;
+
+// File type bits of the mode in cpio headers
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+static INITRD: Once<&'static [u8]> = Once::new();
+
+enum Kind {
+    File,
+    Directory,
+    Symlink,
+    // Devices, FIFOs and hard links
+    Other
+}
+
+struct Entry<'a> {
+    path: String,
+    kind: Kind,
+    data: &'a [u8]
+}
+
+/// Keep the archive loaded by the bootloader, which has to stay mapped forever.
+pub unsafe fn init(address: usize, len: usize) {
+    INITRD.call_once(|| core::slice::from_raw_parts(address as *const u8, len));
+}
+
+pub fn get() -> Option<&'static [u8]> {
+    INITRD.get().copied()
+}
+
+/// Extract all files of the archive below `root` in the VFS, returning the number of entries.
+pub async fn unpack(data: &[u8], root: &str) -> Result<usize> {
+    let entries = if data.starts_with(CPIO_MAGIC) {
+        parse_cpio(data)?
+    } else if data.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 5) == Some(b"ustar") {
+        parse_tar(data)?
+    } else {
+        return Err(Error::Corrupted("Unknown archive format"));
+    };
+
+    let vfs = &runtime().vfs;
+    for entry in &entries {
+        let path = super::vfs::normalize(root, &entry.path);
+
+        // Archives don't always contain the leading directories
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            mkdir_all(parent).await?;
+        }
+
+        match entry.kind {
+            Kind::Directory => mkdir_all(&path).await?,
+            Kind::File => {
+                let mut file = match vfs.create(&path).await {
+                    Ok(file) => file,
+                    // Later entries replace earlier ones, like when extracting with tar
+                    Err(Error::Exists) => {
+                        vfs.remove(&path).await?;
+                        vfs.create(&path).await?
+                    },
+                    Err(err) => return Err(err)
+                };
+                file.write_all(entry.data).await?;
+                file.close().await?;
+            },
+            Kind::Symlink | Kind::Other => {
+                let kind = if let Kind::Symlink = entry.kind { "symlink" } else { "special file" };
+                writeln!(runtime().console.lock(), "initrd: skipping {} {}", kind, path).unwrap();
+            }
+        }
+    }
+    Ok(entries.len())
+}
+
+async fn mkdir_all(path: &str) -> Result<()> {
+    let mut current = String::new();
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        current = format!("{}/{}", current, name);
+        match runtime().vfs.mkdir(&current).await {
+            Ok(()) | Err(Error::Exists) => {},
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(())
+}
+
+// Archive in the newc format, with or without checksums
+fn parse_cpio(data: &[u8]) -> Result<Vec<Entry<'_>>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let header = data.get(offset..offset + CPIO_HEADER_SIZE).ok_or(Error::Corrupted("Truncated cpio header"))?;
+        if !header.starts_with(CPIO_MAGIC) {
+            return Err(Error::Corrupted("Invalid cpio magic"));
+        }
+
+        // Thirteen fields of eight hexadecimal digits follow the magic
+        let field = |index: usize| {
+            let start = 6 + index * 8;
+            str::from_utf8(&header[start..start + 8]).ok()
+                .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+                .ok_or(Error::Corrupted("Invalid cpio header field"))
+        };
+        let mode = field(1)?;
+        let size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        let name_start = offset + CPIO_HEADER_SIZE;
+        let name = data.get(name_start..name_start + name_size.saturating_sub(1))
+            .and_then(|name| str::from_utf8(name).ok())
+            .ok_or(Error::Corrupted("Invalid cpio file name"))?;
+        if name == CPIO_TRAILER {
+            return Ok(entries);
+        }
+
+        // Name and data are both padded to four bytes
+        let data_start = align(name_start + name_size, 4);
+        let contents = data.get(data_start..data_start + size).ok_or(Error::Corrupted("Truncated cpio data"))?;
+        offset = align(data_start + size, 4);
+
+        let kind = match mode & S_IFMT {
+            S_IFREG => Kind::File,
+            S_IFDIR => Kind::Directory,
+            S_IFLNK => Kind::Symlink,
+            _ => Kind::Other
+        };
+        if name != "." {
+            entries.push(Entry {
+                path: name.to_string(),
+                kind,
+                data: contents
+            });
+        }
+    }
+}
+
+// Archive in the ustar format, ending at the first empty block
+fn parse_tar(data: &[u8]) -> Result<Vec<Entry<'_>>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while let Some(header) = data.get(offset..offset + TAR_BLOCK_SIZE) {
+        if header.iter().all(|byte| *byte == 0) {
+            break;
+        }
+        if &header[TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 5] != b"ustar" {
+            return Err(Error::Corrupted("Invalid tar magic"));
+        }
+
+        let size = octal(&header[124..136])?;
+        let name = string(&header[0..100])?;
+        let prefix = string(&header[345..500])?;
+
+        let data_start = offset + TAR_BLOCK_SIZE;
+        let contents = data.get(data_start..data_start + size).ok_or(Error::Corrupted("Truncated tar data"))?;
+        offset = align(data_start + size, TAR_BLOCK_SIZE);
+
+        let kind = match header[156] {
+            b'0' | 0 => Kind::File,
+            b'5' => Kind::Directory,
+            b'2' => Kind::Symlink,
+            _ => Kind::Other
+        };
+
+        // Long names are split over the prefix and the name
+        let path = match prefix.is_empty() {
+            true => name.to_string(),
+            false => format!("{}/{}", prefix, name)
+        };
+        entries.push(Entry {
+            path,
+            kind,
+            data: contents
+        });
+    }
+    Ok(entries)
+}
+
+// NUL terminated string field
+fn string(field: &[u8]) -> Result<&str> {
+    let len = field.iter().position(|byte| *byte == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..len]).map_err(|_| Error::Corrupted("Invalid tar file name"))
+}
+
+// Octal number field, padded with spaces or NULs
+fn octal(field: &[u8]) -> Result<usize> {
+    let digits = string(field)?.trim_matches(' ');
+    usize::from_str_radix(digits, 8).map_err(|_| Error::Corrupted("Invalid tar header field"))
+}
+
+fn align(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) / alignment * alignment
+}
@@ -4,25 +4,280 @@ use alloc::sync::Arc;
 
 use async_trait::async_trait;
 
-use futures_util::stream::BoxStream;
+use core::fmt;
 
+use crate::block::{Block, SECTOR_SIZE};
+use crate::time::DateTime;
+
+use self::ext2::Ext2;
+use self::vfat::{FatType, VFat};
+
+pub mod ext2;
+pub mod initrd;
+pub mod mount;
+pub mod tmpfs;
 pub mod vfat;
+pub mod vfs;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FsType {
+    Fat(FatType),
+    Ext2,
+    Tmpfs
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    NotFound,
+    Exists,
+    NotADirectory,
+    IsADirectory,
+    NotEmpty,
+    ReadOnly,
+    NoSpace,
+    InvalidName,
+    InvalidArgument,
+    FileTooLarge,
+    Busy,
+    CrossDevice,
+    TooManyLinks,
+    // On-disk structures that don't make sense
+    Corrupted(&'static str),
+    // Errors of the underlying device
+    Io(&'static str)
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+// Not all variants are used within the kernel yet
+#[allow(dead_code)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64)
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Metadata {
+    pub file_type: FileType,
+    pub size: u64,
+    // Unix permission bits, like 0o644
+    pub mode: u16,
+    pub created: Option<DateTime>,
+    pub modified: Option<DateTime>,
+    pub accessed: Option<DateTime>
+}
 
 #[async_trait]
 pub trait FileSystem: Send + Sync {
-    async fn open(self: Arc<Self>, path: &str) -> Result<Box<dyn File>, &'static str>;
-    async fn open_node(self: Arc<Self>, inode: u64, len: u64) -> Result<Box<dyn File>, &'static str>;
-    async fn listdir(&self, path: &str) -> Result<BoxStream<FileEntry>, &'static str>;
+    async fn open(self: Arc<Self>, path: &str) -> Result<Box<dyn File>>;
+    async fn open_dir(self: Arc<Self>, path: &str) -> Result<Box<dyn Directory>>;
+    async fn stat(&self, path: &str) -> Result<Metadata>;
+
+    /// Whether names differing only in ASCII case refer to different files.
+    fn case_sensitive(&self) -> bool {
+        true
+    }
+
+    /// Free space in bytes, if the filesystem keeps track of it.
+    fn free_space(&self) -> Option<u64> {
+        None
+    }
+
+    /// Create an empty file, failing if the path already exists.
+    async fn create(self: Arc<Self>, _path: &str) -> Result<Box<dyn File>> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn mkdir(&self, _path: &str) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Remove a file or an empty directory.
+    async fn remove(&self, _path: &str) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn rename(&self, _from: &str, _to: &str) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Write out all changes and mark the filesystem as cleanly unmounted.
+    async fn sync(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub struct FileEntry {
     pub inode: u64,
     pub name: String,
-    pub length: usize,
+    pub metadata: Metadata
 }
 
+/// Open file with a position used by `read`, `write` and `seek`.
 #[async_trait]
 pub trait File: Send + Sync {
-    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str>;
+    fn stat(&self) -> Metadata;
+
+    /// Whether writes are refused, by default when the file has no write permission.
+    fn read_only(&self) -> bool {
+        self.stat().mode & 0o222 == 0
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+    async fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Write the whole buffer, failing when the file stops accepting data.
+    async fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        let mut written = 0;
+        while written < buf.len() {
+            match self.write(&buf[written..]).await? {
+                0 => return Err(Error::NoSpace),
+                len => written += len
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the position, which may go beyond the end of the file, returning the new position.
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64>;
+
+    /// Read at `offset` without moving the file position, returning zero at the end of the file.
+    async fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize>;
+
+    async fn write_at(&self, _buf: &[u8], _offset: u64) -> Result<usize> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn truncate(&self, _size: u64) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Make sure written data reached the device.
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn close(self: Box<Self>) -> Result<()> {
+        self.flush().await
+    }
+}
+
+/// Open directory yielding its entries in order.
+#[async_trait]
+pub trait Directory: Send + Sync {
+    /// Next entry, or `None` once all entries were returned.
+    async fn next_entry(&mut self) -> Result<Option<FileEntry>>;
+}
+
+impl Metadata {
+    /// Directory without timestamps, like the root directory of FAT.
+    pub fn directory() -> Self {
+        Self {
+            file_type: FileType::Directory,
+            size: 0,
+            mode: 0o755,
+            created: None,
+            modified: None,
+            accessed: None
+        }
+    }
+}
+
+impl Error {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Error::NotFound => "File not found",
+            Error::Exists => "File exists",
+            Error::NotADirectory => "Not a directory",
+            Error::IsADirectory => "Is a directory",
+            Error::NotEmpty => "Directory not empty",
+            Error::ReadOnly => "Read-only filesystem",
+            Error::NoSpace => "No space left on device",
+            Error::InvalidName => "Invalid file name",
+            Error::InvalidArgument => "Invalid argument",
+            Error::FileTooLarge => "File too large",
+            Error::Busy => "Device or resource busy",
+            Error::CrossDevice => "Cross-device link",
+            Error::TooManyLinks => "Too many levels of symbolic links",
+            Error::Corrupted(msg) | Error::Io(msg) => msg
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Block devices report errors as strings
+impl From<&'static str> for Error {
+    fn from(msg: &'static str) -> Self {
+        Error::Io(msg)
+    }
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileType::File => write!(f, "file"),
+            FileType::Directory => write!(f, "directory"),
+            FileType::Symlink => write!(f, "symlink")
+        }
+    }
+}
+
+impl fmt::Display for FsType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsType::Fat(fat_type) => write!(f, "{}", fat_type),
+            FsType::Ext2 => write!(f, "ext2"),
+            FsType::Tmpfs => write!(f, "tmpfs")
+        }
+    }
+}
+
+/// Position after seeking from `current` in a file of `size` bytes.
+pub fn seek_position(current: u64, size: u64, pos: SeekFrom) -> Result<u64> {
+    let (base, offset) = match pos {
+        SeekFrom::Start(offset) => return Ok(offset),
+        SeekFrom::Current(offset) => (current, offset),
+        SeekFrom::End(offset) => (size, offset)
+    };
+    base.checked_add_signed(offset).ok_or(Error::InvalidArgument)
+}
+
+/// Detect the filesystem from the contents of the device.
+pub async fn probe(block: &dyn Block) -> Result<Option<FsType>> {
+    // Enough for the ext2 superblock, devices too small for it can still hold FAT
+    let mut buf = [0u8; 2048];
+    let sectors = (block.sector_count() as usize).min(buf.len() / SECTOR_SIZE);
+    block.read(&mut buf[..sectors * SECTOR_SIZE], 0).await?;
+
+    if Ext2::probe(&buf[ext2::SUPERBLOCK_OFFSET..]) {
+        return Ok(Some(FsType::Ext2));
+    }
+    Ok(VFat::probe(buf[..SECTOR_SIZE].try_into().unwrap()).map(FsType::Fat))
+}
+
+pub async fn open(fs_type: FsType, block: Arc<dyn Block>) -> Result<Arc<dyn FileSystem>> {
+    match fs_type {
+        FsType::Fat(_) => Ok(Arc::new(VFat::new(block).await?)),
+        FsType::Ext2 => Ok(Arc::new(Ext2::new(block).await?)),
+        // Lives in memory instead of on a device
+        FsType::Tmpfs => Err(Error::InvalidArgument)
+    }
 }
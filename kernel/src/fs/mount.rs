@@ -0,0 +1,118 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+
+use core::fmt::Write;
+
+use futures_util::{FutureExt, StreamExt};
+
+use crate::block::Block;
+use crate::drivers::registry::{DeviceClass, DeviceEvent};
+use crate::runtime::runtime;
+
+use super::initrd;
+use super::tmpfs::Tmpfs;
+use super::{FileSystem, FsType};
+
+pub struct Mount {
+    pub path: String,
+    pub device: String,
+    pub fs_type: FsType,
+    pub fs: Arc<dyn FileSystem>
+}
+
+/// Mount the filesystems of block devices as they appear, after the initrd and before the defaults.
+pub async fn run() {
+    let mut events = runtime().devices.subscribe(DeviceClass::Block);
+    mount_initrd().await;
+
+    // Disks found during boot, and their partitions, get the first chance to become the root
+    while let Some(Some(event)) = events.next().now_or_never() {
+        handle(event).await;
+    }
+    mount_defaults().await;
+
+    while let Some(event) = events.next().await {
+        handle(event).await;
+    }
+}
+
+async fn handle(event: DeviceEvent) {
+    match event {
+        DeviceEvent::Added(info) => {
+            let Some(block) = info.block() else {
+                return;
+            };
+
+            // Disks with a partition table are mounted through their partitions, which get registered by the scan
+            if info.parent.is_some() || runtime().block_devices.unpartitioned(&info.name).await {
+                automount(&info.name, block).await;
+            }
+        },
+        DeviceEvent::Removed(info) => runtime().vfs.unmount_device(&info.name)
+    }
+}
+
+/// Mount a recognised filesystem on the device, the first one at the root and others below /mnt.
+async fn automount(device: &str, block: Arc<dyn Block>) {
+    let fs_type = match super::probe(block.as_ref()).await {
+        Ok(Some(fs_type)) => fs_type,
+        Ok(None) => return,
+        Err(err) => {
+            writeln!(runtime().console.lock(), "{}: probe failed: {}", device, err).unwrap();
+            return;
+        }
+    };
+
+    let result = match super::open(fs_type, block).await {
+        Ok(fs) => {
+            let vfs = &runtime().vfs;
+            let path = match vfs.get("/") {
+                Some(_) => format!("/mnt/{}", device),
+                None => "/".to_string()
+            };
+            vfs.mount(&path, device, fs_type, fs).await.map(|_| path)
+        },
+        Err(err) => Err(err)
+    };
+
+    match result {
+        Ok(path) => writeln!(runtime().console.lock(), "Mounted {} ({}) at {}", device, fs_type, path).unwrap(),
+        Err(err) => writeln!(runtime().console.lock(), "{}: mount failed: {}", device, err).unwrap()
+    }
+}
+
+/// Unpack the archive loaded by the bootloader into a tmpfs at the root, before any disk can claim it.
+async fn mount_initrd() {
+    let Some(data) = initrd::get() else {
+        return;
+    };
+
+    let fs = Arc::new(Tmpfs::new());
+    let result = match runtime().vfs.mount("/", "initrd", FsType::Tmpfs, fs).await {
+        Ok(()) => initrd::unpack(data, "/").await,
+        Err(err) => Err(err)
+    };
+
+    match result {
+        Ok(count) => writeln!(runtime().console.lock(), "Unpacked {} entries of initrd at /", count).unwrap(),
+        Err(err) => writeln!(runtime().console.lock(), "initrd: {}", err).unwrap()
+    }
+}
+
+/// Mount a tmpfs at /tmp, and at the root when no disk provided one.
+async fn mount_defaults() {
+    let vfs = &runtime().vfs;
+    let paths = match vfs.get("/") {
+        Some(_) => ["/tmp"].as_slice(),
+        None => ["/", "/tmp"].as_slice()
+    };
+
+    for path in paths {
+        let fs = Arc::new(Tmpfs::new());
+        match vfs.mount(path, "tmpfs", FsType::Tmpfs, fs).await {
+            Ok(()) => writeln!(runtime().console.lock(), "Mounted tmpfs at {}", path).unwrap(),
+            Err(err) => writeln!(runtime().console.lock(), "tmpfs: mount at {} failed: {}", path, err).unwrap()
+        }
+    }
+}
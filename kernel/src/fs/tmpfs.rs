@@ -0,0 +1,412 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use async_trait::async_trait;
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use spin::{Once, RwLock};
+
+use crate::ALLOCATOR;
+use crate::arch::{Arch, PAGE_SIZE};
+use crate::arch::system::System;
+use crate::runtime::runtime;
+use crate::time::DateTime;
+
+use super::{Directory, Error, File, FileEntry, FileSystem, FileType, Metadata, Result, SeekFrom, seek_position};
+
+const MAX_NAME_LENGTH: usize = 255;
+const ROOT_INODE: u64 = 1;
+
+// Memory used by the file data of all instances, files give it back when dropped
+struct Usage {
+    used: AtomicUsize,
+    limit: usize
+}
+
+static USAGE: Once<Usage> = Once::new();
+
+// File data is allocated in pages, missing pages of sparse files read as zeroes
+struct FileData {
+    size: u64,
+    pages: BTreeMap<u64, Box<[u8]>>
+}
+
+enum Content {
+    File(FileData),
+    Directory(BTreeMap<String, Arc<RwLock<Node>>>)
+}
+
+struct Node {
+    inode: u64,
+    content: Content,
+    created: DateTime,
+    modified: DateTime
+}
+
+/// Filesystem keeping everything in memory, all instances together hold at most half of the heap.
+pub struct Tmpfs {
+    root: Arc<RwLock<Node>>,
+    next_inode: AtomicU64
+}
+
+struct TmpFile {
+    node: Arc<RwLock<Node>>,
+    position: u64
+}
+
+struct TmpDirectory {
+    entries: vec::IntoIter<FileEntry>
+}
+
+impl Usage {
+    fn reserve(&self, bytes: usize) -> Result<()> {
+        self.used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| used.checked_add(bytes).filter(|total| *total <= self.limit))
+            .map(|_| ())
+            .map_err(|_| Error::NoSpace)
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+// Budget of half the heap that was free when the first instance was created
+fn usage() -> &'static Usage {
+    USAGE.call_once(|| Usage {
+        used: AtomicUsize::new(0),
+        limit: Arch::without_interrupts(|| ALLOCATOR.lock().free()) / 2
+    })
+}
+
+impl FileData {
+    fn read(&self, buf: &mut [u8], offset: u64) -> usize {
+        let len = buf.len().min(self.size.saturating_sub(offset) as usize);
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let page_offset = (position % PAGE_SIZE as u64) as usize;
+            let count = (len - done).min(PAGE_SIZE - page_offset);
+            match self.pages.get(&(position / PAGE_SIZE as u64)) {
+                Some(page) => buf[done..done + count].copy_from_slice(&page[page_offset..page_offset + count]),
+                None => buf[done..done + count].fill(0)
+            }
+            done += count;
+        }
+        len
+    }
+
+    fn write(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+        let end = offset.checked_add(buf.len() as u64).ok_or(Error::FileTooLarge)?;
+
+        // Reserve all new pages up front, so a failed write doesn't change the file
+        let pages = offset / PAGE_SIZE as u64..end.div_ceil(PAGE_SIZE as u64);
+        let new_pages = pages.filter(|index| !self.pages.contains_key(index)).count();
+        usage().reserve(new_pages.checked_mul(PAGE_SIZE).ok_or(Error::FileTooLarge)?)?;
+
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let page_offset = (position % PAGE_SIZE as u64) as usize;
+            let count = (buf.len() - done).min(PAGE_SIZE - page_offset);
+            let page = self.pages.entry(position / PAGE_SIZE as u64).or_insert_with(|| vec![0u8; PAGE_SIZE].into_boxed_slice());
+            page[page_offset..page_offset + count].copy_from_slice(&buf[done..done + count]);
+            done += count;
+        }
+        self.size = self.size.max(end);
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: u64) {
+        let removed = self.pages.split_off(&size.div_ceil(PAGE_SIZE as u64));
+        usage().release(removed.len() * PAGE_SIZE);
+
+        // Growing the file again has to read zeroes beyond the current end
+        let tail = (size % PAGE_SIZE as u64) as usize;
+        if let Some(page) = self.pages.get_mut(&(size / PAGE_SIZE as u64)).filter(|_| tail > 0) {
+            page[tail..].fill(0);
+        }
+        self.size = size;
+    }
+}
+
+impl Drop for FileData {
+    fn drop(&mut self) {
+        usage().release(self.pages.len() * PAGE_SIZE);
+    }
+}
+
+impl Node {
+    fn new(inode: u64, content: Content) -> Arc<RwLock<Self>> {
+        let now = runtime().clock.now();
+        Arc::new(RwLock::new(Self {
+            inode,
+            content,
+            created: now,
+            modified: now
+        }))
+    }
+
+    fn metadata(&self) -> Metadata {
+        let (file_type, size, mode) = match &self.content {
+            Content::File(data) => (FileType::File, data.size, 0o644),
+            Content::Directory(_) => (FileType::Directory, 0, 0o755)
+        };
+
+        Metadata {
+            file_type,
+            size,
+            mode,
+            created: Some(self.created),
+            modified: Some(self.modified),
+            // Reads don't update the access time
+            accessed: None
+        }
+    }
+
+    fn entries(&mut self) -> Result<&mut BTreeMap<String, Arc<RwLock<Node>>>> {
+        match &mut self.content {
+            Content::Directory(entries) => Ok(entries),
+            Content::File(_) => Err(Error::NotADirectory)
+        }
+    }
+
+    fn file(&mut self) -> &mut FileData {
+        match &mut self.content {
+            Content::File(data) => data,
+            Content::Directory(_) => unreachable!("Directories are never opened as files")
+        }
+    }
+}
+
+impl Tmpfs {
+    pub fn new() -> Self {
+        // Fix the budget at the first instance, while most of the heap is still free
+        usage();
+        Self {
+            root: Node::new(ROOT_INODE, Content::Directory(BTreeMap::new())),
+            next_inode: AtomicU64::new(ROOT_INODE + 1)
+        }
+    }
+
+    fn lookup(&self, path: &str) -> Result<Arc<RwLock<Node>>> {
+        // Ancestors of the current node, for going back up with `..`
+        let mut parents = Vec::new();
+        let mut node = self.root.clone();
+        for name in path.split('/').filter(|name| !name.is_empty() && *name != ".") {
+            if name == ".." {
+                node = parents.pop().unwrap_or(node);
+                continue;
+            }
+
+            let next = node.write().entries()?.get(name).cloned().ok_or(Error::NotFound)?;
+            parents.push(core::mem::replace(&mut node, next));
+        }
+        Ok(node)
+    }
+
+    // Directory containing the last component of the path and its name
+    fn parent<'a>(&self, path: &'a str) -> Result<(Arc<RwLock<Node>>, &'a str)> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() || name == "." || name == ".." || name.len() > MAX_NAME_LENGTH {
+            return Err(Error::InvalidName);
+        }
+
+        let parent = self.lookup(parent)?;
+        parent.write().entries()?;
+        Ok((parent, name))
+    }
+
+    fn add(&self, path: &str, content: Content) -> Result<Arc<RwLock<Node>>> {
+        let (parent, name) = self.parent(path)?;
+        let mut parent = parent.write();
+        let entries = parent.entries()?;
+        if entries.contains_key(name) {
+            return Err(Error::Exists);
+        }
+
+        let node = Node::new(self.next_inode.fetch_add(1, Ordering::Relaxed), content);
+        entries.insert(name.to_string(), node.clone());
+        parent.modified = runtime().clock.now();
+        Ok(node)
+    }
+}
+
+#[async_trait]
+impl FileSystem for Tmpfs {
+    async fn open(self: Arc<Self>, path: &str) -> Result<Box<dyn File>> {
+        let node = self.lookup(path)?;
+        if let Content::Directory(_) = node.read().content {
+            return Err(Error::IsADirectory);
+        }
+
+        Ok(Box::new(TmpFile {
+            node,
+            position: 0
+        }))
+    }
+
+    async fn open_dir(self: Arc<Self>, path: &str) -> Result<Box<dyn Directory>> {
+        let node = self.lookup(path)?;
+        let mut node = node.write();
+
+        // Entries are collected at once, so changes after opening are not seen
+        let entries: Vec<FileEntry> = node.entries()?.iter().map(|(name, child)| {
+            let child = child.read();
+            FileEntry {
+                inode: child.inode,
+                name: name.clone(),
+                metadata: child.metadata()
+            }
+        }).collect();
+        Ok(Box::new(TmpDirectory {
+            entries: entries.into_iter()
+        }))
+    }
+
+    async fn stat(&self, path: &str) -> Result<Metadata> {
+        Ok(self.lookup(path)?.read().metadata())
+    }
+
+    fn free_space(&self) -> Option<u64> {
+        let usage = usage();
+        Some(usage.limit.saturating_sub(usage.used.load(Ordering::SeqCst)) as u64)
+    }
+
+    async fn create(self: Arc<Self>, path: &str) -> Result<Box<dyn File>> {
+        let node = self.add(path, Content::File(FileData {
+            size: 0,
+            pages: BTreeMap::new()
+        }))?;
+
+        Ok(Box::new(TmpFile {
+            node,
+            position: 0
+        }))
+    }
+
+    async fn mkdir(&self, path: &str) -> Result<()> {
+        self.add(path, Content::Directory(BTreeMap::new()))?;
+        Ok(())
+    }
+
+    async fn remove(&self, path: &str) -> Result<()> {
+        let (parent, name) = self.parent(path)?;
+        let mut parent = parent.write();
+        let entries = parent.entries()?;
+        let node = entries.get(name).ok_or(Error::NotFound)?;
+        if let Content::Directory(children) = &node.read().content {
+            if !children.is_empty() {
+                return Err(Error::NotEmpty);
+            }
+        }
+
+        // Open files keep their data until they are closed
+        entries.remove(name);
+        parent.modified = runtime().clock.now();
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let (source, from_name) = self.parent(from)?;
+        let (target, to_name) = self.parent(to)?;
+        let node = source.write().entries()?.get(from_name).cloned().ok_or(Error::NotFound)?;
+        if Arc::ptr_eq(&source, &target) && from_name == to_name {
+            return Ok(());
+        }
+
+        // Like unlinking it first, a file replaces a file and a directory an empty directory
+        let existing = target.write().entries()?.get(to_name).cloned();
+        if let Some(existing) = existing {
+            match (&node.read().content, &existing.read().content) {
+                (Content::File(_), Content::File(_)) => {},
+                (Content::Directory(_), Content::Directory(children)) if children.is_empty() => {},
+                (Content::Directory(_), Content::Directory(_)) => return Err(Error::NotEmpty),
+                _ => return Err(Error::Exists)
+            }
+        }
+
+        // A directory can't be moved below itself, which would disconnect it from the tree
+        if contains(&node, &target) {
+            return Err(Error::InvalidArgument);
+        }
+
+        let now = runtime().clock.now();
+        {
+            let mut source = source.write();
+            source.entries()?.remove(from_name);
+            source.modified = now;
+        }
+        let mut target = target.write();
+        target.entries()?.insert(to_name.to_string(), node);
+        target.modified = now;
+        Ok(())
+    }
+}
+
+// Whether `node` is `target` or one of its ancestors
+fn contains(node: &Arc<RwLock<Node>>, target: &Arc<RwLock<Node>>) -> bool {
+    if Arc::ptr_eq(node, target) {
+        return true;
+    }
+
+    match &node.read().content {
+        Content::Directory(entries) => entries.values().any(|child| contains(child, target)),
+        Content::File(_) => false
+    }
+}
+
+#[async_trait]
+impl File for TmpFile {
+    fn stat(&self) -> Metadata {
+        self.node.read().metadata()
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.read_at(buf, self.position).await?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = self.write_at(buf, self.position).await?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let size = self.node.read().metadata().size;
+        self.position = seek_position(self.position, size, pos)?;
+        Ok(self.position)
+    }
+
+    async fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        Ok(self.node.write().file().read(buf, offset))
+    }
+
+    async fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        let mut node = self.node.write();
+        node.file().write(buf, offset)?;
+        node.modified = runtime().clock.now();
+        Ok(buf.len())
+    }
+
+    async fn truncate(&self, size: u64) -> Result<()> {
+        let mut node = self.node.write();
+        node.file().truncate(size);
+        node.modified = runtime().clock.now();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Directory for TmpDirectory {
+    async fn next_entry(&mut self) -> Result<Option<FileEntry>> {
+        Ok(self.entries.next())
+    }
+}
@@ -1,31 +1,38 @@
 use alloc::boxed::Box;
-use alloc::borrow::ToOwned;
-use alloc::string::ToString;
-use alloc::sync::Arc;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
 
 use async_trait::async_trait;
 
-use core::future;
-use core::str;
+use core::fmt::{self, Write};
+use core::mem::size_of;
 
-use futures_util::stream::{BoxStream, self};
-use futures_util::StreamExt;
+use spin::Mutex;
 
-use crate::block::Block;
+use crate::block::{Block, SECTOR_SIZE};
+use crate::runtime::runtime;
+use crate::tasks::mutex::AsyncMutex;
+use crate::time::DateTime;
 
-use super::{FileSystem, File, FileEntry};
+use super::{Directory, Error, File, FileEntry, FileSystem, FileType, Metadata, Result, SeekFrom, seek_position};
 
+/// BIOS parameter block in the boot sector.
+#[derive(Copy, Clone)]
 #[repr(C, packed)]
-pub struct DriverParameterBlock {
+struct DriverParameterBlock {
     rest2: [u8; 11],
     bytes_per_sector: u16,
     sectors_per_cluster: u8,
-    pub reserved_sectors: u16,
-    pub num_fats: u8,
+    reserved_sectors: u16,
+    num_fats: u8,
     root_entries: u16,
     total_sectors_16: u16,
     media_descriptor: u8,
-    pub sectors_per_fat_16: u16,
+    sectors_per_fat_16: u16,
     sectors_per_track: u16,
     num_heads: u16,
     hidden_sectors: u32,
@@ -47,7 +54,8 @@ pub struct DriverParameterBlock {
 pub struct Entry {
     pub name: [u8; 11],
     attributes: u8,
-    reserved: u8,
+    // Lowercase flags for the base name and extension set by Windows NT
+    case_flags: u8,
     created_time_tenths: u8,
     created_time: u16,
     created_date: u16,
@@ -59,125 +67,1482 @@ pub struct Entry {
     size: u32,
 }
 
-pub struct VFat16 {
-    header: DriverParameterBlock,
+/// Part of a long file name, stored before the short entry it belongs to.
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+struct LongNameEntry {
+    order: u8,
+    name1: [u16; 5],
+    attributes: u8,
+    kind: u8,
+    checksum: u8,
+    name2: [u16; 6],
+    first_cluster: u16,
+    name3: [u16; 2]
+}
+
+// Long name being assembled from the entries preceding a short entry
+struct LongName {
+    checksum: u8,
+    // Order of the next expected entry, zero once complete
+    next: u8,
+    chars: Vec<u16>
+}
+
+/// FAT32 FSInfo sector with allocation hints.
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+struct FsInfo {
+    lead_signature: u32,
+    reserved1: [u8; 480],
+    struct_signature: u32,
+    free_count: u32,
+    next_free: u32,
+    reserved2: [u8; 12],
+    trail_signature: u32
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32
+}
+
+// Location of the filesystem structures, derived from the BPB
+struct Layout {
+    fat_type: FatType,
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    sectors_per_fat: u64,
+    num_fats: u64,
+    // Only FAT kept up to date when FAT32 mirroring is disabled
+    active_fat: Option<u64>,
+    // Fixed root directory region of FAT12 and FAT16
+    root_start: u64,
+    // First cluster of the FAT32 root directory
+    root_cluster: u32,
+    data_start: u64,
+    total_sectors: u64,
+    clusters: u32,
+    fs_info_sector: u64
+}
+
+// In-memory copy of the FAT with the allocation state
+struct FatState {
+    fat_type: FatType,
+    fat: Vec<u8>,
+    // Sectors of the FAT changed since they were last written
+    dirty_sectors: BTreeSet<u64>,
+    free_clusters: u32,
+    // Where to start looking for free clusters
+    next_free: u32,
+    // Whether the volume is marked as not cleanly unmounted
+    dirty: bool
+}
+
+// Position of a short entry in a directory
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Location {
+    sector: u64,
+    offset: usize
+}
+
+struct DirEntry {
+    name: String,
+    entry: Entry,
+    // Slot of the first long name entry, the same as `slot` without a long name
+    first_slot: usize,
+    slot: usize,
+    location: Location
+}
+
+// Contents of a directory with the sectors it is stored in
+struct RawDirectory {
+    cluster: u32,
+    // Empty for the fixed root directory region
+    clusters: Vec<u32>,
+    sectors: Vec<u64>,
+    data: Vec<u8>
+}
+
+// Offsets from the entry mask of the values marking bad clusters and the end of a chain
+const BAD_CLUSTER_OFFSET: u32 = 8;
+const END_OF_CHAIN_OFFSET: u32 = 7;
+// Data clusters are numbered from two
+const FIRST_CLUSTER: u32 = 2;
+// Directory entries refer to the root directory as cluster zero
+const ROOT_CLUSTER: u32 = 0;
+// Entry of the second cluster, which holds the clean shutdown flag
+const FLAGS_CLUSTER: u32 = 1;
+const MAX_DIRECTORY_ENTRIES: usize = 65536;
+const MAX_NAME_LENGTH: usize = 255;
+
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x41615252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x61417272;
+const FS_INFO_TRAIL_SIGNATURE: u32 = 0xAA550000;
+// Unknown free cluster count or next free cluster in the FSInfo sector
+const FS_INFO_UNKNOWN: u32 = 0xFFFFFFFF;
+
+const FAT32_MIRRORING_DISABLED: u16 = 0x80;
+const FAT32_ACTIVE_FAT_MASK: u16 = 0x0F;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+// Read-only, hidden, system and volume ID together mark a long name entry
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+const DELETED: u8 = 0xE5;
+// Stands for a leading 0xE5 byte in a name, which would mark the entry deleted
+const KANJI_E5: u8 = 0x05;
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_NAME_CHARS: usize = 13;
+
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXTENSION: u8 = 0x10;
+
+// Characters allowed in short names besides uppercase letters and digits
+const SHORT_NAME_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+const INVALID_NAME_CHARS: &str = "\"*/:<>?\\|";
+
+impl Entry {
+    fn new(name: [u8; 11], attributes: u8, cluster: u32) -> Self {
+        let (date, time) = runtime().clock.now().to_fat();
+        let mut entry = Self {
+            name,
+            attributes,
+            case_flags: 0,
+            created_time_tenths: 0,
+            created_time: time,
+            created_date: date,
+            accessed_date: date,
+            first_cluster_high: 0,
+            modified_time: time,
+            modified_date: date,
+            first_cluster_low: 0,
+            size: 0
+        };
+        entry.set_first_cluster(cluster);
+        entry
+    }
+
+    fn is_directory(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    // The high half is only used by FAT32 and zero otherwise
+    fn first_cluster(&self) -> u32 {
+        (self.first_cluster_high as u32) << 16 | self.first_cluster_low as u32
+    }
+
+    fn set_first_cluster(&mut self, cluster: u32) {
+        self.first_cluster_high = (cluster >> 16) as u16;
+        self.first_cluster_low = cluster as u16;
+    }
+
+    // Record a change of the content, which marks the file for backup
+    fn update(&mut self, cluster: u32, size: usize) {
+        let (date, time) = runtime().clock.now().to_fat();
+        self.set_first_cluster(cluster);
+        self.size = size as u32;
+        self.modified_date = date;
+        self.modified_time = time;
+        self.attributes |= ATTR_ARCHIVE;
+    }
+
+    fn metadata(&self) -> Metadata {
+        let (file_type, mode) = match self.is_directory() {
+            true => (FileType::Directory, 0o755),
+            false => (FileType::File, 0o644)
+        };
+
+        Metadata {
+            file_type,
+            size: self.size as u64,
+            // FAT has no owners, only a read-only attribute
+            mode: if self.attributes & ATTR_READ_ONLY != 0 { mode & !0o222 } else { mode },
+            created: DateTime::from_fat(self.created_date, self.created_time),
+            modified: DateTime::from_fat(self.modified_date, self.modified_time),
+            accessed: DateTime::from_fat(self.accessed_date, 0)
+        }
+    }
+
+    /// Name in 8.3 format, like `HELLO.TXT`.
+    fn short_name(&self) -> String {
+        let decode = |bytes: &[u8], lowercase: bool| -> String {
+            let mut part: String = bytes.iter().map(|byte| char::from(*byte)).collect();
+            part.truncate(part.trim_end_matches(' ').len());
+            if lowercase {
+                part.make_ascii_lowercase();
+            }
+            part
+        };
+
+        let mut name = self.name;
+        if name[0] == KANJI_E5 {
+            name[0] = DELETED;
+        }
+
+        let mut short_name = decode(&name[..8], self.case_flags & LOWERCASE_BASE != 0);
+        let extension = decode(&name[8..], self.case_flags & LOWERCASE_EXTENSION != 0);
+        if !extension.is_empty() {
+            short_name.push('.');
+            short_name.push_str(&extension);
+        }
+        short_name
+    }
+}
+
+impl fmt::Display for FatType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FatType::Fat12 => write!(f, "fat12"),
+            FatType::Fat16 => write!(f, "fat16"),
+            FatType::Fat32 => write!(f, "fat32")
+        }
+    }
+}
+
+impl LongName {
+    // Add an entry, dropping the name when entries are missing or out of order
+    fn add(long_name: &mut Option<LongName>, entry: &LongNameEntry) {
+        let order = entry.order & !LAST_LONG_ENTRY;
+        if entry.order & LAST_LONG_ENTRY != 0 && order > 0 {
+            *long_name = Some(LongName {
+                checksum: entry.checksum,
+                next: order,
+                chars: vec![0xFFFF; order as usize * LONG_NAME_CHARS]
+            });
+        }
+
+        match long_name {
+            Some(name) if name.next == order && name.next > 0 && name.checksum == entry.checksum => {
+                let chars = { entry.name1 }.into_iter().chain(entry.name2).chain(entry.name3);
+                let start = (order as usize - 1) * LONG_NAME_CHARS;
+                name.chars[start..start + LONG_NAME_CHARS].iter_mut().zip(chars).for_each(|(dest, c)| *dest = c);
+                name.next -= 1;
+            },
+            _ => *long_name = None
+        }
+    }
+
+    // The name if it is complete and belongs to the short entry
+    fn finish(self, entry: &Entry) -> Option<String> {
+        if self.next != 0 || self.checksum != short_name_checksum(&entry.name) {
+            return None;
+        }
+
+        // Names are terminated by a null and padded with 0xFFFF when not filling the last entry
+        let chars = self.chars.into_iter().take_while(|c| *c != 0 && *c != 0xFFFF);
+        Some(char::decode_utf16(chars).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect())
+    }
+
+    /// Entries storing `name` in the order they appear in the directory.
+    fn entries(name: &str, checksum: u8) -> Vec<LongNameEntry> {
+        let mut chars: Vec<u16> = name.encode_utf16().collect();
+        let count = (chars.len() + LONG_NAME_CHARS - 1) / LONG_NAME_CHARS;
+        if chars.len() < count * LONG_NAME_CHARS {
+            chars.push(0);
+        }
+        chars.resize(count * LONG_NAME_CHARS, 0xFFFF);
+
+        (1..=count).rev().map(|order| {
+            let part = &chars[(order - 1) * LONG_NAME_CHARS..order * LONG_NAME_CHARS];
+            LongNameEntry {
+                order: order as u8 | if order == count { LAST_LONG_ENTRY } else { 0 },
+                name1: part[0..5].try_into().unwrap(),
+                attributes: ATTR_LONG_NAME,
+                kind: 0,
+                checksum,
+                name2: part[5..11].try_into().unwrap(),
+                first_cluster: 0,
+                name3: part[11..13].try_into().unwrap()
+            }
+        }).collect()
+    }
+}
+
+fn short_name_checksum(name: &[u8; 11]) -> u8 {
+    name.iter().fold(0u8, |sum, byte| sum.rotate_right(1).wrapping_add(*byte))
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.encode_utf16().count() > MAX_NAME_LENGTH
+        || name.ends_with(['.', ' ']) || name.chars().any(|c| c < ' ' || INVALID_NAME_CHARS.contains(c)) {
+        return Err(Error::InvalidName);
+    }
+    Ok(())
+}
+
+// The short name and case flags when the name fits 8.3 without a long name
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || extension.contains('.') {
+        return None;
+    }
+
+    let mut case_flags = 0;
+    for (part, flag) in [(base, LOWERCASE_BASE), (extension, LOWERCASE_EXTENSION)] {
+        let lowercase = part.bytes().any(|c| c.is_ascii_lowercase());
+        if lowercase && part.bytes().any(|c| c.is_ascii_uppercase()) {
+            return None;
+        } else if lowercase {
+            case_flags |= flag;
+        }
+
+        if !part.bytes().all(|c| c.is_ascii_alphanumeric() || SHORT_NAME_SPECIAL.contains(&c)) {
+            return None;
+        }
+    }
+
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.to_ascii_uppercase().as_bytes());
+    Some((short_name, case_flags))
+}
+
+// Unique short name for a long name, like `LONGNA~1.TXT`
+fn generate_short_name(name: &str, existing: &[[u8; 11]]) -> Result<[u8; 11]> {
+    let convert = |part: &str, len: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|c| *c != ' ' && *c != '.')
+            .map(|c| match c.to_ascii_uppercase() as u32 {
+                c if c < 0x80 && (char::from(c as u8).is_ascii_alphanumeric() || SHORT_NAME_SPECIAL.contains(&(c as u8))) => c as u8,
+                _ => b'_'
+            })
+            .take(len)
+            .collect()
+    };
+
+    let name = name.trim_start_matches('.');
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    let base = convert(base, 8);
+    let extension = convert(extension, 3);
+
+    for number in 1..1000000 {
+        let tail = format!("~{}", number);
+        let len = base.len().min(8 - tail.len());
+
+        let mut short_name = [b' '; 11];
+        short_name[..len].copy_from_slice(&base[..len]);
+        short_name[len..len + tail.len()].copy_from_slice(tail.as_bytes());
+        short_name[8..8 + extension.len()].copy_from_slice(&extension);
+        if !existing.contains(&short_name) {
+            return Ok(short_name);
+        }
+    }
+    Err(Error::Exists)
+}
+
+// Split a path into its parent directory and the last component
+fn split_path(path: &str) -> Result<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    check_name(name)?;
+    Ok((parent, name))
+}
+
+fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+pub struct VFat {
+    layout: Layout,
+    state: Mutex<FatState>,
+    // Serializes changes to directories and the FAT
+    lock: AsyncMutex<()>,
+    // State shared by all handles of an open file, by the location of its entry
+    open_files: Mutex<BTreeMap<Location, Weak<Mutex<FileState>>>>,
     block: Arc<dyn Block>,
 }
 
-impl VFat16 {
-    pub async fn new(block: Arc<dyn Block>) -> Result<Self, &'static str> {
+impl FatType {
+    fn bits(&self) -> u64 {
+        match self {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32
+        }
+    }
+
+    // Bits of an entry in use, FAT32 reserves the upper four
+    fn mask(&self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFFFFFF
+        }
+    }
+
+    // Bit in the entry of the second cluster set when the volume was unmounted cleanly
+    fn clean_bit(&self) -> Option<u32> {
+        match self {
+            FatType::Fat12 => None,
+            FatType::Fat16 => Some(0x8000),
+            FatType::Fat32 => Some(0x08000000)
+        }
+    }
+}
+
+impl Layout {
+    /// Validate the BPB in the boot sector.
+    fn parse(buf: &[u8; 512]) -> Result<Self> {
+        if buf[510..512] != [0x55, 0xAA] {
+            return Err(Error::Corrupted("Missing boot sector signature"));
+        }
+
+        let bpb = unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const DriverParameterBlock) };
+        // Sectors are addressed directly on the device
+        if bpb.bytes_per_sector as usize != SECTOR_SIZE {
+            return Err(Error::Corrupted("Unsupported sector size"));
+        }
+
+        let sectors_per_cluster = bpb.sectors_per_cluster as u64;
+        let sectors_per_fat = match bpb.sectors_per_fat_16 {
+            0 => bpb.sectors_per_fat_32 as u64,
+            sectors => sectors as u64
+        };
+        let total_sectors = match bpb.total_sectors_16 {
+            0 => bpb.total_sectors_32 as u64,
+            sectors => sectors as u64
+        };
+        let fat_start = bpb.reserved_sectors as u64;
+        let root_start = fat_start + bpb.num_fats as u64 * sectors_per_fat;
+        let root_sectors = (bpb.root_entries as u64 * size_of::<Entry>() as u64 + SECTOR_SIZE as u64 - 1) / SECTOR_SIZE as u64;
+        let data_start = root_start + root_sectors;
+        if !sectors_per_cluster.is_power_of_two() || fat_start == 0 || bpb.num_fats == 0 || sectors_per_fat == 0 || data_start >= total_sectors {
+            return Err(Error::Corrupted("Invalid BIOS parameter block"));
+        }
+
+        // The cluster count alone determines the FAT type
+        let clusters = ((total_sectors - data_start) / sectors_per_cluster) as u32;
+        let fat_type = match clusters {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32
+        };
+
+        // FAT32 keeps the root directory in a cluster chain instead of a fixed region
+        let root_valid = match fat_type {
+            FatType::Fat32 => bpb.root_entries == 0 && bpb.root_cluster >= FIRST_CLUSTER && bpb.root_cluster - FIRST_CLUSTER < clusters,
+            _ => bpb.root_entries != 0
+        };
+        if !root_valid {
+            return Err(Error::Corrupted("Invalid root directory"));
+        }
+
+        if sectors_per_fat * SECTOR_SIZE as u64 * 8 / fat_type.bits() < clusters as u64 + FIRST_CLUSTER as u64 {
+            return Err(Error::Corrupted("FAT too small for all clusters"));
+        }
+
+        let active_fat = match fat_type == FatType::Fat32 && bpb.ext_flags & FAT32_MIRRORING_DISABLED != 0 {
+            true => Some((bpb.ext_flags & FAT32_ACTIVE_FAT_MASK) as u64).filter(|fat| *fat < bpb.num_fats as u64).ok_or(Error::Corrupted("Invalid active FAT"))?.into(),
+            false => None
+        };
+
+        Ok(Self {
+            fat_type,
+            sectors_per_cluster,
+            fat_start,
+            sectors_per_fat,
+            num_fats: bpb.num_fats as u64,
+            active_fat,
+            root_start,
+            root_cluster: bpb.root_cluster,
+            data_start,
+            total_sectors,
+            clusters,
+            fs_info_sector: bpb.fs_info_sector as u64
+        })
+    }
+
+    // FSInfo lives in the reserved sectors and only exists on FAT32
+    fn has_fs_info(&self) -> bool {
+        self.fat_type == FatType::Fat32 && self.fs_info_sector > 0 && self.fs_info_sector < self.fat_start
+    }
+}
+
+impl FatState {
+    fn get(&self, cluster: u32) -> Option<u32> {
+        let cluster = cluster as usize;
+        let entry = match self.fat_type {
+            FatType::Fat12 => {
+                // Entries are packed in 12 bits, odd ones in the upper bits
+                let offset = cluster + cluster / 2;
+                let value = u16::from_le_bytes(self.fat.get(offset..offset + 2)?.try_into().unwrap());
+                match cluster % 2 {
+                    0 => value as u32 & 0xFFF,
+                    _ => value as u32 >> 4
+                }
+            },
+            FatType::Fat16 => u16::from_le_bytes(self.fat.get(cluster * 2..cluster * 2 + 2)?.try_into().unwrap()) as u32,
+            FatType::Fat32 => u32::from_le_bytes(self.fat.get(cluster * 4..cluster * 4 + 4)?.try_into().unwrap())
+        };
+        Some(entry & self.fat_type.mask())
+    }
+
+    fn set(&mut self, cluster: u32, value: u32) {
+        let cluster = cluster as usize;
+        let (offset, len) = match self.fat_type {
+            FatType::Fat12 => {
+                let offset = cluster + cluster / 2;
+                let old = u16::from_le_bytes(self.fat[offset..offset + 2].try_into().unwrap());
+                let new = match cluster % 2 {
+                    0 => (old & 0xF000) | (value & 0xFFF) as u16,
+                    _ => (old & 0x000F) | ((value & 0xFFF) as u16) << 4
+                };
+                self.fat[offset..offset + 2].copy_from_slice(&new.to_le_bytes());
+                (offset, 2)
+            },
+            FatType::Fat16 => {
+                self.fat[cluster * 2..cluster * 2 + 2].copy_from_slice(&(value as u16).to_le_bytes());
+                (cluster * 2, 2)
+            },
+            FatType::Fat32 => {
+                // The upper four bits are reserved and have to be preserved
+                let old = u32::from_le_bytes(self.fat[cluster * 4..cluster * 4 + 4].try_into().unwrap());
+                let new = (old & !self.fat_type.mask()) | (value & self.fat_type.mask());
+                self.fat[cluster * 4..cluster * 4 + 4].copy_from_slice(&new.to_le_bytes());
+                (cluster * 4, 4)
+            }
+        };
+
+        // FAT12 entries can cross a sector boundary
+        self.dirty_sectors.insert((offset / SECTOR_SIZE) as u64);
+        self.dirty_sectors.insert(((offset + len - 1) / SECTOR_SIZE) as u64);
+    }
+
+    fn set_clean(&mut self, clean: bool) {
+        if let (Some(bit), Some(flags)) = (self.fat_type.clean_bit(), self.get(FLAGS_CLUSTER)) {
+            self.set(FLAGS_CLUSTER, if clean { flags | bit } else { flags & !bit });
+        }
+        self.dirty = !clean;
+    }
+}
+
+impl RawDirectory {
+    fn slots(&self) -> usize {
+        self.data.len() / size_of::<Entry>()
+    }
+
+    fn slot(&self, slot: usize) -> &[u8] {
+        &self.data[slot * size_of::<Entry>()..(slot + 1) * size_of::<Entry>()]
+    }
+
+    fn location(&self, slot: usize) -> Location {
+        let offset = slot * size_of::<Entry>();
+        Location {
+            sector: self.sectors[offset / SECTOR_SIZE],
+            offset: offset % SECTOR_SIZE
+        }
+    }
+
+    // Slots after the first unused one are all free
+    fn end(&self) -> usize {
+        (0..self.slots()).find(|slot| self.slot(*slot)[0] == 0).unwrap_or(self.slots())
+    }
+
+    /// Named files and directories, up to the end marker.
+    fn entries(&self) -> Vec<DirEntry> {
+        let mut entries = Vec::new();
+        let mut long_name = None;
+        let mut first_slot = 0;
+        for slot in 0..self.end() {
+            let raw = self.slot(slot);
+            let entry = unsafe { core::ptr::read_unaligned(raw.as_ptr() as *const Entry) };
+            if entry.name[0] == DELETED {
+                long_name = None;
+            } else if entry.attributes & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                if long_name.is_none() {
+                    first_slot = slot;
+                }
+                LongName::add(&mut long_name, unsafe { &core::ptr::read_unaligned(raw.as_ptr() as *const LongNameEntry) });
+            } else if entry.attributes & ATTR_VOLUME_ID != 0 {
+                long_name = None;
+            } else {
+                let (name, first_slot) = match long_name.take().and_then(|name: LongName| name.finish(&entry)) {
+                    Some(name) => (name, first_slot),
+                    None => (entry.short_name(), slot)
+                };
+                entries.push(DirEntry {
+                    name,
+                    entry,
+                    first_slot,
+                    slot,
+                    location: self.location(slot)
+                });
+            }
+        }
+        entries
+    }
+
+    // Names are matched case insensitively like FAT does, though only for ASCII
+    fn find(&self, name: &str) -> Option<DirEntry> {
+        self.entries().into_iter().find(|entry| entry.name.eq_ignore_ascii_case(name))
+    }
+}
+
+impl VFat {
+    /// FAT type of the filesystem described by the boot sector, if any.
+    pub fn probe(buf: &[u8; 512]) -> Option<FatType> {
+        Layout::parse(buf).ok().map(|layout| layout.fat_type)
+    }
+
+    pub async fn new(block: Arc<dyn Block>) -> Result<Self> {
         let mut buf = [0u8; 512];
         block.read(buf.as_mut_slice(), 0).await?;
-        let header: DriverParameterBlock = unsafe { core::mem::transmute(buf) };
+        let layout = Layout::parse(&buf)?;
+        if layout.total_sectors > block.sector_count() {
+            return Err(Error::Corrupted("Filesystem larger than device"));
+        }
+
+        let mut fat = vec![0u8; layout.sectors_per_fat as usize * SECTOR_SIZE];
+        block.read(&mut fat, layout.fat_start + layout.active_fat.unwrap_or(0) * layout.sectors_per_fat).await?;
+        let mut state = FatState {
+            fat_type: layout.fat_type,
+            fat,
+            dirty_sectors: BTreeSet::new(),
+            free_clusters: 0,
+            next_free: FIRST_CLUSTER,
+            dirty: false
+        };
+        state.free_clusters = (FIRST_CLUSTER..layout.clusters + FIRST_CLUSTER).filter(|cluster| state.get(*cluster) == Some(0)).count() as u32;
+
+        // Only the allocation hint is taken from FSInfo, the free count is known from the FAT
+        if layout.has_fs_info() {
+            block.read(buf.as_mut_slice(), layout.fs_info_sector).await?;
+            let info = unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const FsInfo) };
+            if info.lead_signature == FS_INFO_LEAD_SIGNATURE && info.next_free >= FIRST_CLUSTER && info.next_free - FIRST_CLUSTER < layout.clusters {
+                state.next_free = info.next_free;
+            }
+        }
+
+        if let (Some(bit), Some(flags)) = (layout.fat_type.clean_bit(), state.get(FLAGS_CLUSTER)) {
+            state.dirty = flags & bit == 0;
+            if state.dirty {
+                writeln!(runtime().console.lock(), "FAT volume was not cleanly unmounted").unwrap();
+            }
+        }
 
         Ok(Self {
-            header,
+            layout,
+            state: Mutex::new(state),
+            lock: AsyncMutex::new(()),
+            open_files: Mutex::new(BTreeMap::new()),
             block
         })
     }
-}
 
-struct ListDirState {
-    sector: u32,
-    offset: u16,
-    buf: [u8; 512]
+    fn cluster_size(&self) -> usize {
+        self.layout.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.layout.data_start + (cluster - FIRST_CLUSTER) as u64 * self.layout.sectors_per_cluster
+    }
+
+    // Directory entries refer to the root directory as cluster zero, even on FAT32
+    fn is_root(&self, cluster: u32) -> bool {
+        cluster == ROOT_CLUSTER || (self.layout.fat_type == FatType::Fat32 && cluster == self.layout.root_cluster)
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        match self.block.read_only() {
+            true => Err(Error::ReadOnly),
+            false => Ok(())
+        }
+    }
+
+    fn is_open(&self, location: Location) -> bool {
+        self.open_files.lock().get(&location).is_some_and(|state| state.strong_count() > 0)
+    }
+
+    // Open a file with the state of its other handles, so they all see the same chain and size
+    fn open_file(self: Arc<Self>, entry: Entry, location: Location) -> Result<VFatFile> {
+        let mut files = self.open_files.lock();
+        let state = match files.get(&location).and_then(Weak::upgrade) {
+            Some(state) => state,
+            None => {
+                let clusters = self.chain(entry.first_cluster())?;
+                if clusters.len() < (entry.size as usize + self.cluster_size() - 1) / self.cluster_size() {
+                    return Err(Error::Corrupted("Cluster chain shorter than file"));
+                }
+
+                let state = Arc::new(Mutex::new(FileState {
+                    location,
+                    entry,
+                    clusters
+                }));
+                files.insert(location, Arc::downgrade(&state));
+                state
+            }
+        };
+        drop(files);
+
+        Ok(VFatFile {
+            position: 0,
+            state,
+            block: self.block.clone(),
+            fs: self
+        })
+    }
+
+    // Follow an open file to the new location of its entry
+    fn move_open_file(&self, from: Location, to: Location) {
+        let mut files = self.open_files.lock();
+        if let Some(state) = files.remove(&from) {
+            if let Some(state) = state.upgrade() {
+                state.lock().location = to;
+            }
+            files.insert(to, state);
+        }
+    }
+
+    async fn read_directory(&self, cluster: u32) -> Result<RawDirectory> {
+        let (clusters, sectors): (Vec<u32>, Vec<u64>) = match self.is_root(cluster) && self.layout.fat_type != FatType::Fat32 {
+            true => (Vec::new(), (self.layout.root_start..self.layout.data_start).collect()),
+            false => {
+                let first = if self.is_root(cluster) { self.layout.root_cluster } else { cluster };
+                let clusters = self.chain(first)?;
+                let sectors = clusters.iter().flat_map(|cluster| {
+                    let sector = self.cluster_sector(*cluster);
+                    sector..sector + self.layout.sectors_per_cluster
+                }).collect();
+                (clusters, sectors)
+            }
+        };
+
+        // Read runs of consecutive sectors with a single request
+        let mut data = vec![0u8; sectors.len() * SECTOR_SIZE];
+        let mut start = 0;
+        while start < sectors.len() {
+            let mut end = start + 1;
+            while end < sectors.len() && sectors[end] == sectors[end - 1] + 1 {
+                end += 1;
+            }
+            self.block.read(&mut data[start * SECTOR_SIZE..end * SECTOR_SIZE], sectors[start]).await?;
+            start = end;
+        }
+
+        Ok(RawDirectory {
+            cluster,
+            clusters,
+            sectors,
+            data
+        })
+    }
+
+    /// Entry the path refers to, `None` for the root directory which has no entry.
+    async fn lookup(&self, path: &str) -> Result<Option<DirEntry>> {
+        let mut current: Option<DirEntry> = None;
+        for name in path.split('/').filter(|name| !name.is_empty() && *name != ".") {
+            let dir = match &current {
+                Some(entry) if !entry.entry.is_directory() => return Err(Error::NotADirectory),
+                Some(entry) => entry.entry.first_cluster(),
+                None => ROOT_CLUSTER
+            };
+
+            // The root directory has no dot entries, its parent is itself
+            if self.is_root(dir) && name == ".." {
+                continue;
+            }
+
+            let entry = self.read_directory(dir).await?.find(name).ok_or(Error::NotFound)?;
+            current = match entry.entry.is_directory() && self.is_root(entry.entry.first_cluster()) {
+                true => None,
+                false => Some(entry)
+            };
+        }
+        Ok(current)
+    }
+
+    // First cluster of the directory at the path
+    async fn directory_cluster(&self, path: &str) -> Result<u32> {
+        match self.lookup(path).await? {
+            Some(entry) if !entry.entry.is_directory() => Err(Error::NotADirectory),
+            Some(entry) => Ok(entry.entry.first_cluster()),
+            None => Ok(ROOT_CLUSTER)
+        }
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<u32> {
+        let bad_cluster = self.layout.fat_type.mask() - BAD_CLUSTER_OFFSET;
+        match cluster {
+            cluster if cluster == bad_cluster => Err(Error::Corrupted("Bad cluster in chain")),
+            cluster if cluster < FIRST_CLUSTER || cluster - FIRST_CLUSTER >= self.layout.clusters => Err(Error::Corrupted("Invalid cluster in chain")),
+            cluster => Ok(cluster)
+        }
+    }
+
+    /// Clusters of the chain starting at `first`, which is zero for empty files.
+    fn chain(&self, first: u32) -> Result<Vec<u32>> {
+        let mut chain = Vec::new();
+        if first == 0 {
+            return Ok(chain);
+        }
+
+        let state = self.state.lock();
+        let end_of_chain = self.layout.fat_type.mask() - END_OF_CHAIN_OFFSET;
+        let mut cluster = self.check_cluster(first)?;
+        loop {
+            // A chain can't be longer than the number of clusters without looping
+            if chain.len() >= self.layout.clusters as usize {
+                return Err(Error::Corrupted("Loop in cluster chain"));
+            }
+            chain.push(cluster);
+
+            let next = state.get(cluster).ok_or(Error::Corrupted("Cluster outside of FAT"))?;
+            if next >= end_of_chain {
+                return Ok(chain);
+            }
+            cluster = self.check_cluster(next)?;
+        }
+    }
+
+    // Grow or shrink the chain to hold `size` bytes
+    fn resize_chain(&self, clusters: &mut Vec<u32>, size: usize) -> Result<()> {
+        let needed = (size + self.cluster_size() - 1) / self.cluster_size();
+        let mut state = self.state.lock();
+        if needed > clusters.len() {
+            let new = self.allocate(&mut state, needed - clusters.len(), clusters.last().copied())?;
+            clusters.extend(new);
+        } else if needed < clusters.len() {
+            for cluster in clusters.drain(needed..) {
+                state.set(cluster, 0);
+                state.free_clusters += 1;
+            }
+            if let Some(last) = clusters.last() {
+                state.set(*last, self.layout.fat_type.mask());
+            }
+        }
+        Ok(())
+    }
+
+    // Allocate a chain of free clusters, linked to the end of an existing chain
+    fn allocate(&self, state: &mut FatState, count: usize, last: Option<u32>) -> Result<Vec<u32>> {
+        if count > state.free_clusters as usize {
+            return Err(Error::NoSpace);
+        }
+
+        let mut clusters = Vec::with_capacity(count);
+        let mut cluster = state.next_free;
+        while clusters.len() < count {
+            if cluster < FIRST_CLUSTER || cluster - FIRST_CLUSTER >= self.layout.clusters {
+                cluster = FIRST_CLUSTER;
+            }
+            if state.get(cluster) == Some(0) {
+                state.set(cluster, self.layout.fat_type.mask());
+                clusters.push(cluster);
+            }
+            cluster += 1;
+        }
+
+        let mut previous = last;
+        for cluster in &clusters {
+            if let Some(previous) = previous {
+                state.set(previous, *cluster);
+            }
+            previous = Some(*cluster);
+        }
+        state.next_free = cluster;
+        state.free_clusters -= count as u32;
+        Ok(clusters)
+    }
+
+    fn free_chain(&self, first: u32) -> Result<()> {
+        let clusters = self.chain(first)?;
+        let mut state = self.state.lock();
+        for cluster in clusters {
+            state.set(cluster, 0);
+            state.free_clusters += 1;
+        }
+        Ok(())
+    }
+
+    // Mark the volume dirty on disk before its first change, so an interrupted update can be detected
+    async fn begin_update(&self) -> Result<()> {
+        {
+            let mut state = self.state.lock();
+            if state.dirty {
+                return Ok(());
+            }
+            state.set_clean(false);
+        }
+
+        // Try again with the next change when the flag didn't reach the disk
+        let result = match self.write_fat().await {
+            Ok(()) => self.block.flush().await.map_err(Error::from),
+            Err(err) => Err(err)
+        };
+        if result.is_err() {
+            self.state.lock().dirty = false;
+        }
+        result
+    }
+
+    /// Write changed sectors of the FAT to all copies and update FSInfo.
+    async fn write_fat(&self) -> Result<()> {
+        let (sectors, free_clusters, next_free) = {
+            let mut state = self.state.lock();
+            let sectors: Vec<(u64, Vec<u8>)> = core::mem::take(&mut state.dirty_sectors).into_iter()
+                .map(|sector| (sector, state.fat[sector as usize * SECTOR_SIZE..][..SECTOR_SIZE].to_vec()))
+                .collect();
+            (sectors, state.free_clusters, state.next_free)
+        };
+        if sectors.is_empty() {
+            return Ok(());
+        }
+
+        let copies = match self.layout.active_fat {
+            Some(fat) => fat..fat + 1,
+            None => 0..self.layout.num_fats
+        };
+        for copy in copies {
+            for (sector, data) in &sectors {
+                self.block.write(data, self.layout.fat_start + copy * self.layout.sectors_per_fat + sector).await?;
+            }
+        }
+
+        if self.layout.has_fs_info() {
+            let mut buf = [0u8; SECTOR_SIZE];
+            self.block.read(&mut buf, self.layout.fs_info_sector).await?;
+            let mut info = unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const FsInfo) };
+            if info.lead_signature == FS_INFO_LEAD_SIGNATURE && info.struct_signature == FS_INFO_STRUCT_SIGNATURE && info.trail_signature == FS_INFO_TRAIL_SIGNATURE {
+                info.free_count = free_clusters;
+                info.next_free = match next_free - FIRST_CLUSTER < self.layout.clusters {
+                    true => next_free,
+                    false => FS_INFO_UNKNOWN
+                };
+                self.block.write(as_bytes(&info), self.layout.fs_info_sector).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Sector containing `offset` of the chain and the bytes stored contiguously from there
+    fn map_offset(&self, clusters: &[u32], offset: usize) -> Result<(u64, usize)> {
+        let cluster_size = self.cluster_size();
+        let index = offset / cluster_size;
+        let cluster_offset = offset % cluster_size;
+        let cluster = *clusters.get(index).ok_or(Error::Corrupted("Offset outside of cluster chain"))?;
+
+        // Extend over physically consecutive clusters
+        let mut end = index + 1;
+        while end < clusters.len() && clusters[end] == clusters[end - 1] + 1 {
+            end += 1;
+        }
+        Ok((self.cluster_sector(cluster) + (cluster_offset / SECTOR_SIZE) as u64, (end - index) * cluster_size - cluster_offset))
+    }
+
+    async fn write_data(&self, clusters: &[u32], offset: usize, buf: &[u8]) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done;
+            let (sector, contiguous) = self.map_offset(clusters, position)?;
+            let sector_offset = position % SECTOR_SIZE;
+            let max_write = (buf.len() - done).min(contiguous);
+
+            // Write whole sectors directly from the buffer in a single request
+            if sector_offset == 0 && max_write >= SECTOR_SIZE {
+                let count = max_write - max_write % SECTOR_SIZE;
+                self.block.write(&buf[done..done + count], sector).await?;
+                done += count;
+                continue;
+            }
+
+            let count = max_write.min(SECTOR_SIZE - sector_offset);
+            let mut sector_buf = [0u8; SECTOR_SIZE];
+            self.block.read(&mut sector_buf, sector).await?;
+            sector_buf[sector_offset..sector_offset + count].copy_from_slice(&buf[done..done + count]);
+            self.block.write(&sector_buf, sector).await?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    // Fill a range of the chain with zeroes, like the gap when writing beyond the end of a file
+    async fn zero_data(&self, clusters: &[u32], offset: usize, len: usize) -> Result<()> {
+        let zeroes = vec![0u8; len.min(self.cluster_size())];
+        let mut done = 0;
+        while done < len {
+            let count = (len - done).min(zeroes.len());
+            self.write_data(clusters, offset + done, &zeroes[..count]).await?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    // Update the short entry of a file after its content changed
+    async fn update_entry(&self, location: Location, cluster: u32, size: usize) -> Result<()> {
+        let mut buf = [0u8; SECTOR_SIZE];
+        self.block.read(&mut buf, location.sector).await?;
+        let raw = &mut buf[location.offset..location.offset + size_of::<Entry>()];
+
+        let mut entry = unsafe { core::ptr::read_unaligned(raw.as_ptr() as *const Entry) };
+        if entry.name[0] == DELETED || entry.name[0] == 0 {
+            return Err(Error::Corrupted("Entry of open file disappeared"));
+        }
+
+        entry.update(cluster, size);
+        raw.copy_from_slice(as_bytes(&entry));
+        Ok(self.block.write(&buf, location.sector).await?)
+    }
+
+    async fn write_slots(&self, dir: &mut RawDirectory, slot: usize, data: &[u8]) -> Result<()> {
+        let start = slot * size_of::<Entry>();
+        dir.data[start..start + data.len()].copy_from_slice(data);
+
+        for index in start / SECTOR_SIZE..(start + data.len() + SECTOR_SIZE - 1) / SECTOR_SIZE {
+            self.block.write(&dir.data[index * SECTOR_SIZE..(index + 1) * SECTOR_SIZE], dir.sectors[index]).await?;
+        }
+        Ok(())
+    }
+
+    // Add a cluster to a directory, the fixed root directory region can't grow
+    async fn extend_directory(&self, dir: &mut RawDirectory) -> Result<()> {
+        let per_cluster = self.cluster_size() / size_of::<Entry>();
+        if dir.clusters.is_empty() || dir.slots() + per_cluster > MAX_DIRECTORY_ENTRIES {
+            return Err(Error::NoSpace);
+        }
+
+        let cluster = self.allocate(&mut self.state.lock(), 1, dir.clusters.last().copied())?[0];
+        let sector = self.cluster_sector(cluster);
+        self.block.write_zeroes(sector, self.layout.sectors_per_cluster).await?;
+
+        dir.clusters.push(cluster);
+        dir.sectors.extend(sector..sector + self.layout.sectors_per_cluster);
+        dir.data.resize(dir.data.len() + self.cluster_size(), 0);
+        Ok(())
+    }
+
+    /// Add an entry with a long name when needed, returning the location of its short entry.
+    async fn add_entry(&self, dir: &mut RawDirectory, name: &str, mut entry: Entry) -> Result<Location> {
+        let existing: Vec<[u8; 11]> = dir.entries().iter().map(|entry| entry.entry.name).collect();
+        let long_name = match exact_short_name(name) {
+            Some((short_name, case_flags)) if !existing.contains(&short_name) => {
+                entry.name = short_name;
+                entry.case_flags = case_flags;
+                None
+            },
+            _ => {
+                entry.name = generate_short_name(name, &existing)?;
+                entry.case_flags = 0;
+                Some(LongName::entries(name, short_name_checksum(&entry.name)))
+            }
+        };
+
+        let mut data = Vec::new();
+        long_name.iter().flatten().for_each(|long_entry| data.extend_from_slice(as_bytes(long_entry)));
+        data.extend_from_slice(as_bytes(&entry));
+        let count = data.len() / size_of::<Entry>();
+
+        // Find enough consecutive free slots, growing the directory when there are none
+        let slot = loop {
+            let end = dir.end();
+            let mut run = 0;
+            let found = (0..dir.slots()).find(|slot| {
+                run = if *slot >= end || dir.slot(*slot)[0] == DELETED { run + 1 } else { 0 };
+                run == count
+            });
+            if let Some(last) = found {
+                // Move the end marker when using the slots behind it, which may contain garbage
+                if last >= end && last + 1 < dir.slots() {
+                    data.resize(data.len() + size_of::<Entry>(), 0);
+                }
+                break last + 1 - count;
+            }
+            self.extend_directory(dir).await?;
+        };
+
+        self.write_slots(dir, slot, &data).await?;
+        Ok(dir.location(slot + count - 1))
+    }
+
+    async fn remove_entry(&self, dir: &mut RawDirectory, entry: &DirEntry) -> Result<()> {
+        let mut data = dir.data[entry.first_slot * size_of::<Entry>()..(entry.slot + 1) * size_of::<Entry>()].to_vec();
+        data.chunks_mut(size_of::<Entry>()).for_each(|slot| slot[0] = DELETED);
+        self.write_slots(dir, entry.first_slot, &data).await
+    }
+
+    async fn is_empty(&self, cluster: u32) -> Result<bool> {
+        Ok(self.read_directory(cluster).await?.entries().iter().all(|entry| entry.name == "." || entry.name == ".."))
+    }
+
+    // Whether the directory at `cluster` is `ancestor` or inside it
+    async fn is_within(&self, mut cluster: u32, ancestor: u32) -> Result<bool> {
+        for _ in 0..self.layout.clusters {
+            if cluster == ancestor {
+                return Ok(true);
+            } else if self.is_root(cluster) {
+                return Ok(false);
+            }
+            cluster = self.read_directory(cluster).await?.find("..").ok_or(Error::Corrupted("Directory without parent"))?.entry.first_cluster();
+        }
+        Err(Error::Corrupted("Loop in directory tree"))
+    }
+
+    // Create a file or directory entry at the path, which must not exist yet
+    async fn create_entry(&self, path: &str, entry: Entry) -> Result<Location> {
+        let (parent, name) = split_path(path)?;
+        let mut dir = self.read_directory(self.directory_cluster(parent).await?).await?;
+        if dir.find(name).is_some() {
+            return Err(Error::Exists);
+        }
+
+        self.begin_update().await?;
+        self.add_entry(&mut dir, name, entry).await
+    }
 }
 
 #[async_trait]
-impl FileSystem for VFat16 {
-    async fn open(self: Arc<Self>, path: &str) -> Result<Box<dyn File>, &'static str> {
-        let listdir = self.listdir(path).await?;
-        let path = path.to_owned();
-        let mut files = Box::pin(listdir.filter(|entry| future::ready(entry.name == path)));
-        let file = files.next().await.ok_or("File not found")?;
+impl FileSystem for VFat {
+    async fn open(self: Arc<Self>, path: &str) -> Result<Box<dyn File>> {
+        let entry = self.lookup(path).await?.ok_or(Error::IsADirectory)?;
+        if entry.entry.is_directory() {
+            return Err(Error::IsADirectory);
+        }
+
+        Ok(Box::new(self.open_file(entry.entry, entry.location)?))
+    }
+
+    async fn open_dir(self: Arc<Self>, path: &str) -> Result<Box<dyn Directory>> {
+        // Entries are read at once, so changes after opening are not seen
+        let mut entries = self.read_directory(self.directory_cluster(path).await?).await?.entries();
+        // Like the other filesystems, without the entries for the directory itself and its parent
+        entries.retain(|entry| entry.name != "." && entry.name != "..");
+        Ok(Box::new(VFatDirectory {
+            entries: entries.into_iter()
+        }))
+    }
+
+    async fn stat(&self, path: &str) -> Result<Metadata> {
+        Ok(self.lookup(path).await?.map_or_else(Metadata::directory, |entry| entry.entry.metadata()))
+    }
+
+    fn case_sensitive(&self) -> bool {
+        false
+    }
+
+    fn free_space(&self) -> Option<u64> {
+        Some(self.state.lock().free_clusters as u64 * self.cluster_size() as u64)
+    }
+
+    async fn create(self: Arc<Self>, path: &str) -> Result<Box<dyn File>> {
+        self.check_writable()?;
+        let _guard = self.lock.lock().await;
+        let entry = Entry::new([b' '; 11], ATTR_ARCHIVE, 0);
+        let location = self.create_entry(path, entry).await?;
+        self.write_fat().await?;
+        Ok(Box::new(self.clone().open_file(entry, location)?))
+    }
+
+    async fn mkdir(&self, path: &str) -> Result<()> {
+        self.check_writable()?;
+        let _guard = self.lock.lock().await;
+        let (parent, _) = split_path(path)?;
+        let parent = match self.directory_cluster(parent).await? {
+            cluster if self.is_root(cluster) => ROOT_CLUSTER,
+            cluster => cluster
+        };
+
+        self.begin_update().await?;
+        let cluster = self.allocate(&mut self.state.lock(), 1, None)?[0];
+        let sector = self.cluster_sector(cluster);
+        self.block.write_zeroes(sector, self.layout.sectors_per_cluster).await?;
 
-        Ok(Box::new(VFat16File::new(self.clone(), file.length, file.inode as usize)))
+        // New directories start with entries for themselves and their parent
+        let mut data = Vec::new();
+        data.extend_from_slice(as_bytes(&Entry::new(*b".          ", ATTR_DIRECTORY, cluster)));
+        data.extend_from_slice(as_bytes(&Entry::new(*b"..         ", ATTR_DIRECTORY, parent)));
+        data.resize(SECTOR_SIZE, 0);
+        self.block.write(&data, sector).await?;
+
+        if let Err(err) = self.create_entry(path, Entry::new([b' '; 11], ATTR_DIRECTORY, cluster)).await {
+            self.free_chain(cluster)?;
+            self.write_fat().await?;
+            return Err(err);
+        }
+        self.write_fat().await
     }
 
-    async fn open_node(self: Arc<Self>, inode: u64, len: u64) -> Result<Box<dyn File>, &'static str> {
-        let cluster = inode as usize;
-        Ok(Box::new(VFat16File::new(self, len as usize, cluster)))
+    async fn remove(&self, path: &str) -> Result<()> {
+        self.check_writable()?;
+        let _guard = self.lock.lock().await;
+        let (parent, name) = split_path(path)?;
+        let mut dir = self.read_directory(self.directory_cluster(parent).await?).await?;
+        let entry = dir.find(name).ok_or(Error::NotFound)?;
+
+        let cluster = entry.entry.first_cluster();
+        if entry.entry.is_directory() {
+            if !self.is_empty(cluster).await? {
+                return Err(Error::NotEmpty);
+            }
+        } else if self.is_open(entry.location) {
+            return Err(Error::Busy);
+        }
+
+        // Remove the entry before freeing its clusters, so they are never in use twice
+        self.begin_update().await?;
+        self.remove_entry(&mut dir, &entry).await?;
+        self.free_chain(cluster)?;
+        self.write_fat().await
     }
 
-    async fn listdir(&self, _path: &str) -> Result<BoxStream<FileEntry>, &'static str> {
-        let root = self.header.reserved_sectors + self.header.sectors_per_fat_16 * self.header.num_fats as u16;
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.check_writable()?;
+        let _guard = self.lock.lock().await;
+        let (from_parent, from_name) = split_path(from)?;
+        let (to_parent, to_name) = split_path(to)?;
 
-        let _state = ListDirState {
-            // Use sector minus one to load the next sector on the first iteration
-            sector: (root - 1) as u32,
-            offset: 16,
-            buf: [0u8; 512]
+        let source_cluster = self.directory_cluster(from_parent).await?;
+        let target_cluster = self.directory_cluster(to_parent).await?;
+        let mut source = self.read_directory(source_cluster).await?;
+        let entry = source.find(from_name).ok_or(Error::NotFound)?;
+
+        // Renaming within a directory changes both entries in the same copy
+        let same_directory = source.cluster == target_cluster;
+        let mut target = match same_directory {
+            true => None,
+            false => Some(self.read_directory(target_cluster).await?)
         };
-        Ok(stream::unfold(_state, move |mut state| async move {
-            state.offset += 1;
-            if state.offset >= 16 {
-                state.sector += 1;
-                self.block.read(state.buf.as_mut_slice(), state.sector as u64).await.unwrap();
-                state.offset = 0;
+        let existing = target.as_ref().unwrap_or(&source).find(to_name)
+            .filter(|existing| !same_directory || existing.slot != entry.slot);
+
+        // Like removing it first, a file replaces a file and a directory an empty directory
+        if let Some(existing) = &existing {
+            match (entry.entry.is_directory(), existing.entry.is_directory()) {
+                (false, false) if self.is_open(existing.location) => return Err(Error::Busy),
+                (false, false) => {},
+                (true, true) if !self.is_empty(existing.entry.first_cluster()).await? => return Err(Error::NotEmpty),
+                (true, true) => {},
+                _ => return Err(Error::Exists)
             }
+        }
 
-            let entries = unsafe { core::slice::from_raw_parts(state.buf.as_ptr() as *mut Entry, 512 / core::mem::size_of::<Entry>()) };
-            let entry = entries[state.offset as usize];
+        let cluster = entry.entry.first_cluster();
+        if entry.entry.is_directory() && self.is_within(target_cluster, cluster).await? {
+            return Err(Error::InvalidArgument);
+        }
 
-            if entry.name[0] == 0 {
-                None
-            } else {
-                Some((FileEntry {
-                    inode: entry.first_cluster_low as u64,
-                    length: entry.size as usize,
-                    name: str::from_utf8(&entry.name).unwrap_or("Unknown").trim_end().to_string()
-                }, state))
+        // Add the new entry before removing the source, a crash then leaves two names instead of none
+        self.begin_update().await?;
+        let dir = target.as_mut().unwrap_or(&mut source);
+        let replaced = match &existing {
+            Some(existing) => {
+                let slots = dir.data[existing.first_slot * size_of::<Entry>()..(existing.slot + 1) * size_of::<Entry>()].to_vec();
+                self.remove_entry(dir, existing).await?;
+                Some(slots)
+            },
+            None => None
+        };
+        let location = match self.add_entry(dir, to_name, entry.entry).await {
+            Ok(location) => location,
+            Err(err) => {
+                // Adding fails before writing any slots, so the replaced entry can be put back
+                if let (Some(existing), Some(slots)) = (&existing, replaced) {
+                    self.write_slots(dir, existing.first_slot, &slots).await?;
+                }
+                self.write_fat().await?;
+                return Err(err);
+            }
+        };
+
+        // Adding only takes free slots, but look the source up again in the updated copy
+        let entry = match same_directory {
+            true => source.entries().into_iter().find(|other| other.slot == entry.slot).ok_or(Error::Corrupted("Renamed entry disappeared"))?,
+            false => entry
+        };
+        self.remove_entry(&mut source, &entry).await?;
+        self.move_open_file(entry.location, location);
+
+        // A moved directory has to refer to its new parent
+        if entry.entry.is_directory() && !same_directory {
+            let mut dir = self.read_directory(cluster).await?;
+            if let Some(parent) = dir.find("..") {
+                let mut parent_entry = parent.entry;
+                parent_entry.set_first_cluster(if self.is_root(target_cluster) { ROOT_CLUSTER } else { target_cluster });
+                self.write_slots(&mut dir, parent.slot, as_bytes(&parent_entry)).await?;
             }
-        }).boxed())
+        }
+
+        if let Some(existing) = existing {
+            self.free_chain(existing.entry.first_cluster())?;
+        }
+        self.write_fat().await
+    }
+
+    async fn sync(&self) -> Result<()> {
+        if self.block.read_only() {
+            return Ok(());
+        }
+
+        let _guard = self.lock.lock().await;
+        {
+            let mut state = self.state.lock();
+            if state.dirty {
+                state.set_clean(true);
+            }
+        }
+        self.write_fat().await?;
+        Ok(self.block.flush().await?)
     }
 }
 
-struct VFat16File {
-    offset: usize,
-    length: usize,
-    cluster: usize,
-    fs: Arc<VFat16>,
+struct VFatFile {
+    position: u64,
+    state: Arc<Mutex<FileState>>,
+    fs: Arc<VFat>,
     block: Arc<dyn Block>
 }
 
-impl VFat16File {
-    pub fn new(fs: Arc<VFat16>, length: usize, cluster: usize) -> Self {
-        let block = fs.block.clone();
-        Self {
-            offset: 0,
-            length,
-            fs,
-            block,
-            cluster
+struct FileState {
+    // Short entry of the file, updated when its size changes
+    location: Location,
+    // Copy of the short entry, its size is the length of the file
+    entry: Entry,
+    clusters: Vec<u32>
+}
+
+struct VFatDirectory {
+    entries: vec::IntoIter<DirEntry>
+}
+
+impl VFatFile {
+    fn len(&self) -> usize {
+        self.state.lock().entry.size as usize
+    }
+
+    // Change the size of the file, with `data` written at `offset` when growing it
+    async fn update(&self, size: usize, data: Option<(usize, &[u8])>) -> Result<()> {
+        self.fs.check_writable()?;
+        if size > u32::MAX as usize {
+            return Err(Error::FileTooLarge);
+        }
+
+        let _guard = self.fs.lock.lock().await;
+        let (length, mut clusters) = {
+            let state = self.state.lock();
+            (state.entry.size as usize, state.clusters.clone())
+        };
+
+        self.fs.begin_update().await?;
+        self.fs.resize_chain(&mut clusters, size)?;
+        self.state.lock().clusters = clusters.clone();
+
+        // Data beyond the old end of the file reads as zeroes
+        let gap_end = data.map_or(size, |(offset, _)| offset).min(size);
+        if gap_end > length {
+            self.fs.zero_data(&clusters, length, gap_end - length).await?;
+        }
+        if let Some((offset, buf)) = data {
+            self.fs.write_data(&clusters, offset, buf).await?;
+        }
+
+        let cluster = clusters.first().copied().unwrap_or(0);
+        let location = {
+            let mut state = self.state.lock();
+            state.entry.update(cluster, size);
+            state.location
+        };
+        self.fs.write_fat().await?;
+        self.fs.update_entry(location, cluster, size).await
+    }
+}
+
+impl Drop for VFatFile {
+    fn drop(&mut self) {
+        // Forget the shared state with the last handle, new handles are only made with the map locked
+        let mut files = self.fs.open_files.lock();
+        if Arc::strong_count(&self.state) == 1 {
+            files.remove(&self.state.lock().location);
         }
     }
 }
 
 #[async_trait]
-impl File for VFat16File {
-    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
-        let mut data_offset = (self.fs.header.reserved_sectors + self.fs.header.sectors_per_fat_16 * self.fs.header.num_fats as u16) as usize;
-        let cluster_length = self.fs.header.sectors_per_cluster as usize * self.fs.header.bytes_per_sector as usize;
-        data_offset += self.fs.header.sectors_per_cluster as usize * self.cluster;
-
-        let data_left = self.length - self.offset;
-        if data_left == 0 {
+impl File for VFatFile {
+    fn stat(&self) -> Metadata {
+        self.state.lock().entry.metadata()
+    }
+
+    fn read_only(&self) -> bool {
+        self.block.read_only()
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.read_at(buf, self.position).await?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = self.write_at(buf, self.position).await?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.position = seek_position(self.position, self.len() as u64, pos)?;
+        Ok(self.position)
+    }
+
+    async fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        let len = buf.len().min(self.len().saturating_sub(start));
+
+        let mut done = 0;
+        while done < len {
+            let offset = start + done;
+            let (sector, contiguous) = self.fs.map_offset(&self.state.lock().clusters, offset)?;
+            let sector_offset = offset % SECTOR_SIZE;
+            let max_read = (len - done).min(contiguous);
+
+            // Read whole sectors directly into the buffer in a single request
+            if sector_offset == 0 && max_read >= SECTOR_SIZE {
+                let count = max_read - max_read % SECTOR_SIZE;
+                self.block.read(&mut buf[done..done + count], sector).await?;
+                done += count;
+                continue;
+            }
+
+            let count = max_read.min(SECTOR_SIZE - sector_offset);
+            let mut sector_buf = [0u8; SECTOR_SIZE];
+            self.block.read(sector_buf.as_mut(), sector).await?;
+            buf[done..done + count].copy_from_slice(&sector_buf[sector_offset..sector_offset + count]);
+            done += count;
+        }
+        Ok(done)
+    }
+
+    async fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        if buf.is_empty() {
             return Ok(0);
         }
 
-        if self.offset < cluster_length {
-            let current_sector = self.offset / self.fs.header.bytes_per_sector as usize;
-            let sector_offset = self.offset % self.fs.header.bytes_per_sector as usize;
-            let sector_left = self.fs.header.bytes_per_sector as usize - sector_offset;
-            let max_read = buf.len().min(sector_left).min(data_left);
-            let mut sector_buf = [0u8; 512];
-            self.block.read(sector_buf.as_mut(), (data_offset + current_sector) as u64).await?;
+        let offset = usize::try_from(offset).map_err(|_| Error::FileTooLarge)?;
+        let end = offset.checked_add(buf.len()).ok_or(Error::FileTooLarge)?;
+        self.update(end.max(self.len()), Some((offset, buf))).await?;
+        Ok(buf.len())
+    }
 
-            buf[0..max_read].copy_from_slice(&sector_buf[sector_offset..sector_offset + max_read]);
-            self.offset += max_read;
-            Ok(max_read)
-        } else {
-            Err("Not implemented")
-        }
+    async fn truncate(&self, size: u64) -> Result<()> {
+        self.update(usize::try_from(size).map_err(|_| Error::FileTooLarge)?, None).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        Ok(self.block.flush().await?)
+    }
+}
+
+#[async_trait]
+impl Directory for VFatDirectory {
+    async fn next_entry(&mut self) -> Result<Option<FileEntry>> {
+        Ok(self.entries.next().map(|DirEntry { name, entry, .. }| FileEntry {
+            inode: entry.first_cluster() as u64,
+            name,
+            metadata: entry.metadata()
+        }))
     }
 }
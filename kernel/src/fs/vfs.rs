@@ -0,0 +1,343 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use async_trait::async_trait;
+
+use spin::{Mutex, RwLock};
+
+use super::mount::Mount;
+use super::{Directory, Error, File, FileEntry, FileSystem, FileType, FsType, Metadata, Result};
+
+const DENTRY_CACHE_SIZE: usize = 256;
+
+// Recently looked up paths per mount point with their type, `None` for paths that don't exist
+struct DentryCache {
+    entries: BTreeMap<(String, String), Option<FileType>>,
+    // Insertion order for evicting the oldest entries
+    order: VecDeque<(String, String)>,
+    // Changed by every invalidation, so lookups that raced with a change don't cache the old state
+    generation: u64
+}
+
+/// Single namespace of all mounted filesystems.
+pub struct Vfs {
+    mounts: RwLock<BTreeMap<String, Arc<Mount>>>,
+    dentries: Mutex<DentryCache>
+}
+
+// Directory of a filesystem with the mount points below it added
+struct VfsDirectory {
+    inner: Option<Box<dyn Directory>>,
+    mount_points: vec::IntoIter<String>,
+    hidden: BTreeSet<String>
+}
+
+impl DentryCache {
+    fn get(&self, mount: &Mount, path: &str) -> Option<Option<FileType>> {
+        self.entries.get(&key(mount, path)).copied()
+    }
+
+    /// Remember the result of a lookup started at `generation`, unless the cache changed since.
+    fn insert(&mut self, generation: u64, mount: &Mount, path: &str, file_type: Option<FileType>) {
+        if generation != self.generation {
+            return;
+        }
+
+        let key = key(mount, path);
+        if self.entries.insert(key.clone(), file_type).is_none() {
+            self.order.push_back(key);
+        }
+
+        while self.order.len() > DENTRY_CACHE_SIZE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forget the path within the mount and everything below it.
+    fn invalidate(&mut self, mount: &Mount, path: &str) {
+        let (mount_path, path) = key(mount, path);
+        let matches = |(entry_mount, entry): &(String, String)| *entry_mount == mount_path && is_within(entry, &path);
+        self.entries.retain(|entry, _| !matches(entry));
+        self.order.retain(|entry| !matches(entry));
+        self.generation += 1;
+    }
+
+    /// Forget everything cached for the filesystem mounted at the path.
+    fn invalidate_mount(&mut self, mount_path: &str) {
+        self.entries.retain(|(entry_mount, _), _| entry_mount != mount_path);
+        self.order.retain(|(entry_mount, _)| entry_mount != mount_path);
+        self.generation += 1;
+    }
+}
+
+impl Vfs {
+    pub fn new() -> Self {
+        Self {
+            mounts: RwLock::new(BTreeMap::new()),
+            dentries: Mutex::new(DentryCache {
+                entries: BTreeMap::new(),
+                order: VecDeque::new(),
+                generation: 0
+            })
+        }
+    }
+
+    /// Mount at a directory, which doesn't have to exist when nothing is mounted above it.
+    pub async fn mount(&self, path: &str, device: &str, fs_type: FsType, fs: Arc<dyn FileSystem>) -> Result<()> {
+        let path = normalize("/", path);
+        match self.stat(&path).await {
+            Ok(metadata) if metadata.file_type != FileType::Directory => return Err(Error::NotADirectory),
+            Ok(_) | Err(Error::NotFound) => {},
+            Err(err) => return Err(err)
+        }
+
+        let mut mounts = self.mounts.write();
+        if mounts.contains_key(&path) {
+            return Err(Error::Busy);
+        }
+
+        mounts.insert(path.clone(), Arc::new(Mount {
+            path: path.clone(),
+            device: device.to_string(),
+            fs_type,
+            fs
+        }));
+        self.dentries.lock().invalidate_mount(&path);
+        Ok(())
+    }
+
+    /// Write out all changes of the filesystem and detach it, which fails while others are mounted below it.
+    pub async fn unmount(&self, path: &str) -> Result<()> {
+        let path = normalize("/", path);
+        let mount = self.get(&path).ok_or(Error::NotFound)?;
+        if self.mounts.read().keys().any(|other| *other != path && is_within(other, &path)) {
+            return Err(Error::Busy);
+        }
+
+        mount.fs.sync().await?;
+        self.mounts.write().remove(&path);
+        self.dentries.lock().invalidate_mount(&path);
+        Ok(())
+    }
+
+    /// Unmount all filesystems on a device that went away.
+    pub fn unmount_device(&self, device: &str) {
+        let mut mounts = self.mounts.write();
+        let mut dentries = self.dentries.lock();
+        for mount in mounts.values().filter(|mount| mount.device == device) {
+            dentries.invalidate_mount(&mount.path);
+        }
+        mounts.retain(|_, mount| mount.device != device);
+    }
+
+    pub fn get(&self, path: &str) -> Option<Arc<Mount>> {
+        self.mounts.read().get(path).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Arc<Mount>> {
+        self.mounts.read().values().cloned().collect()
+    }
+
+    /// Filesystem containing the path and the path within it.
+    fn resolve(&self, path: &str) -> Result<(Arc<Mount>, String)> {
+        let path = normalize("/", path);
+        let mounts = self.mounts.read();
+        let mount = mounts.values()
+            .filter(|mount| is_within(&path, &mount.path))
+            .max_by_key(|mount| mount.path.len())
+            .ok_or(Error::NotFound)?;
+
+        let relative = match mount.path.as_str() {
+            "/" => path.clone(),
+            prefix => normalize("/", &path[prefix.len()..])
+        };
+        Ok((mount.clone(), relative))
+    }
+
+    // Names of the mount points directly below the path, which may not exist as directories
+    fn mount_points(&self, path: &str) -> Vec<String> {
+        let names: BTreeSet<String> = self.mounts.read().keys()
+            .filter(|mount| *mount != path && is_within(mount, path))
+            .filter_map(|mount| mount[path.len()..].split('/').find(|name| !name.is_empty()).map(str::to_string))
+            .collect();
+        names.into_iter().collect()
+    }
+
+    pub async fn stat(&self, path: &str) -> Result<Metadata> {
+        let path = normalize("/", path);
+        let result = match self.resolve(&path) {
+            Ok((mount, relative)) => {
+                let generation = self.dentries.lock().generation;
+                let result = mount.fs.stat(&relative).await;
+                match &result {
+                    Ok(metadata) => self.dentries.lock().insert(generation, &mount, &relative, Some(metadata.file_type)),
+                    Err(Error::NotFound) => self.dentries.lock().insert(generation, &mount, &relative, None),
+                    Err(_) => {}
+                }
+                result
+            },
+            Err(err) => Err(err)
+        };
+        self.add_mount_points(&path, result)
+    }
+
+    /// Type of the file at the path, answered from the dentry cache when possible.
+    pub async fn file_type(&self, path: &str) -> Result<FileType> {
+        let path = normalize("/", path);
+        let cached = match self.resolve(&path) {
+            Ok((mount, relative)) => self.dentries.lock().get(&mount, &relative),
+            Err(_) => None
+        };
+        match cached {
+            Some(Some(file_type)) => Ok(file_type),
+            Some(None) => Ok(self.add_mount_points(&path, Err(Error::NotFound))?.file_type),
+            None => Ok(self.stat(&path).await?.file_type)
+        }
+    }
+
+    // Leading directories of mount points exist even when the filesystem below has no such directory
+    fn add_mount_points(&self, path: &str, result: Result<Metadata>) -> Result<Metadata> {
+        match result {
+            Err(Error::NotFound) if !self.mount_points(path).is_empty() => Ok(Metadata::directory()),
+            result => result
+        }
+    }
+
+    pub async fn open(&self, path: &str) -> Result<Box<dyn File>> {
+        let (mount, relative) = self.resolve(path)?;
+        mount.fs.clone().open(&relative).await
+    }
+
+    pub async fn open_dir(&self, path: &str) -> Result<Box<dyn Directory>> {
+        let path = normalize("/", path);
+        let mount_points = self.mount_points(&path);
+        let inner = match self.resolve(&path) {
+            Ok((mount, relative)) => mount.fs.clone().open_dir(&relative).await,
+            Err(err) => Err(err)
+        };
+        let inner = match inner {
+            Ok(inner) => Some(inner),
+            Err(Error::NotFound) if !mount_points.is_empty() => None,
+            Err(err) => return Err(err)
+        };
+
+        Ok(Box::new(VfsDirectory {
+            inner,
+            hidden: mount_points.iter().cloned().collect(),
+            mount_points: mount_points.into_iter()
+        }))
+    }
+
+    // Entries are forgotten both before and after the change, lookups during it are not cached
+    pub async fn create(&self, path: &str) -> Result<Box<dyn File>> {
+        let (mount, relative) = self.resolve(path)?;
+        self.dentries.lock().invalidate(&mount, &relative);
+        let result = mount.fs.clone().create(&relative).await;
+        self.dentries.lock().invalidate(&mount, &relative);
+        result
+    }
+
+    pub async fn mkdir(&self, path: &str) -> Result<()> {
+        let (mount, relative) = self.resolve(path)?;
+        self.dentries.lock().invalidate(&mount, &relative);
+        let result = mount.fs.mkdir(&relative).await;
+        self.dentries.lock().invalidate(&mount, &relative);
+        result
+    }
+
+    pub async fn remove(&self, path: &str) -> Result<()> {
+        let path = normalize("/", path);
+        self.check_not_mounted(&path)?;
+        let (mount, relative) = self.resolve(&path)?;
+        self.dentries.lock().invalidate(&mount, &relative);
+        let result = mount.fs.remove(&relative).await;
+        self.dentries.lock().invalidate(&mount, &relative);
+        result
+    }
+
+    /// Rename within a filesystem, moving between filesystems is not supported.
+    pub async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let (from, to) = (normalize("/", from), normalize("/", to));
+        self.check_not_mounted(&from)?;
+        let (from_mount, from_relative) = self.resolve(&from)?;
+        let (to_mount, to_relative) = self.resolve(&to)?;
+        if !Arc::ptr_eq(&from_mount, &to_mount) {
+            return Err(Error::CrossDevice);
+        }
+
+        let invalidate = || {
+            let mut dentries = self.dentries.lock();
+            dentries.invalidate(&from_mount, &from_relative);
+            dentries.invalidate(&from_mount, &to_relative);
+        };
+        invalidate();
+        let result = from_mount.fs.rename(&from_relative, &to_relative).await;
+        invalidate();
+        result
+    }
+
+    // Mount points and the directories leading to them can't be removed or moved
+    fn check_not_mounted(&self, path: &str) -> Result<()> {
+        match self.mounts.read().keys().any(|mount| is_within(mount, path)) {
+            true => Err(Error::Busy),
+            false => Ok(())
+        }
+    }
+}
+
+#[async_trait]
+impl Directory for VfsDirectory {
+    async fn next_entry(&mut self) -> Result<Option<FileEntry>> {
+        // Entries of the filesystem first, without the ones covered by mount points
+        while let Some(inner) = &mut self.inner {
+            match inner.next_entry().await? {
+                Some(entry) if self.hidden.contains(&entry.name) => continue,
+                Some(entry) => return Ok(Some(entry)),
+                None => self.inner = None
+            }
+        }
+
+        Ok(self.mount_points.next().map(|name| FileEntry {
+            inode: 0,
+            name,
+            metadata: Metadata::directory()
+        }))
+    }
+}
+
+/// Absolute path without `.`, `..` and repeated slashes, relative paths start at `cwd`.
+pub fn normalize(cwd: &str, path: &str) -> String {
+    let base = if path.starts_with('/') { "" } else { cwd };
+    let mut components = Vec::new();
+    for component in base.split('/').chain(path.split('/')) {
+        match component {
+            "" | "." => {},
+            ".." => {
+                components.pop();
+            },
+            name => components.push(name)
+        }
+    }
+    format!("/{}", components.join("/"))
+}
+
+// Cache key of a path within the mount, in one case when the filesystem ignores it
+fn key(mount: &Mount, path: &str) -> (String, String) {
+    let path = match mount.fs.case_sensitive() {
+        true => path.to_string(),
+        false => path.to_ascii_lowercase()
+    };
+    (mount.path.clone(), path)
+}
+
+// Whether the normalized path is `dir` or below it
+fn is_within(path: &str, dir: &str) -> bool {
+    dir == "/" || path == dir || (path.starts_with(dir) && path.as_bytes().get(dir.len()) == Some(&b'/'))
+}
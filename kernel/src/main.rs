@@ -1,5 +1,6 @@
 #![feature(abi_x86_interrupt)]
 #![feature(allocator_api)]
+#![feature(asm_const)]
 #![feature(core_intrinsics)]
 #![feature(fn_align)]
 #![feature(naked_functions)]
@@ -17,13 +18,16 @@ mod runtime;
 mod scheduler;
 mod shell;
 mod tasks;
+mod time;
 
 extern crate alloc;
 
 use arch::Arch;
 use arch::system::System;
 
+use core::alloc::{GlobalAlloc, Layout};
 use core::fmt::Write;
+use core::ops::Deref;
 use core::panic::PanicInfo;
 
 use linked_list_allocator::LockedHeap;
@@ -36,7 +40,28 @@ use tasks::executor::Executor;
 use uart_16550::SerialPort;
 
 #[global_allocator]
-pub static ALLOCATOR: LockedHeap = LockedHeap::empty();
+pub static ALLOCATOR: Allocator = Allocator(LockedHeap::empty());
+
+/// Heap which can be used from interrupt handlers without deadlocking on its lock
+pub struct Allocator(LockedHeap);
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        Arch::without_interrupts(|| self.0.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        Arch::without_interrupts(|| self.0.dealloc(ptr, layout))
+    }
+}
+
+impl Deref for Allocator {
+    type Target = LockedHeap;
+
+    fn deref(&self) -> &LockedHeap {
+        &self.0
+    }
+}
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -54,15 +79,18 @@ fn main() -> ! {
 
     let mut executor = Executor::new();
     executor.spawn(Task::new(shell::ios_shell()));
+    executor.spawn(Task::new(runtime().block_devices.run()));
+    executor.spawn(Task::new(fs::mount::run()));
+    executor.spawn(Task::new(block::cache::write_back_periodically()));
 
     loop {
         executor.run_ready_tasks();
-        Arch::sleep();
+        Arch::idle(|| executor.has_ready_tasks());
     }
 }
 
 fn main_cpu(cpu_id: u32) -> ! {
     writeln!(runtime().console.lock(), "Booted CPU: {}", cpu_id).unwrap();
 
-    loop { Arch::sleep() }
+    loop { Arch::idle(|| false) }
 }
@@ -1,8 +1,12 @@
+use alloc::boxed::Box;
 use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
 
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use xmas_elf::{ElfFile, program};
 
 use spin::RwLock;
@@ -10,37 +14,71 @@ use spin::RwLock;
 use crate::arch::{PageTable, KERNEL_ADDRESS_BASE, ThreadState};
 use crate::arch::system::{System, PageMapper, MemoryFlags, ThreadContext};
 use crate::runtime::runtime;
+use crate::scheduler::{CpuSet, Policy, SchedEntity};
 
 const PROCCESS_ADDR: usize = 0x900000000;
 const STACK_ADDR: usize = 0x1000000000;
 
+const KERNEL_STACK_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(pub u64);
+
 pub struct Process {
     entry_point: usize,
-    page_table: Option<PageTable>
+    page_table: Option<PageTable>,
+    // Absolute path that relative paths are resolved against
+    cwd: String
 }
 
 pub struct Thread {
+    pub id: ThreadId,
     pub name: String,
     process: Arc<RwLock<Process>>,
     pub state: ThreadState,
+    pub sched: SchedEntity,
     _stack: Vec<u8>
 }
 
+impl ThreadId {
+    fn new() -> Self {
+        // Zero is used by syscalls to refer to the current thread
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 impl Process {
     pub fn empty() -> Self {
         Self {
             entry_point: 0,
-            page_table: None
+            page_table: None,
+            cwd: String::from("/")
         }
     }
 
     pub fn new() -> Self {
         Self {
             entry_point: 0,
-            page_table: Some(runtime().system.new_user_page_table())
+            page_table: Some(runtime().system.new_user_page_table()),
+            cwd: String::from("/")
         }
     }
 
+    pub fn cwd(&self) -> &str {
+        &self.cwd
+    }
+
+    pub fn set_cwd(&mut self, cwd: String) {
+        self.cwd = cwd;
+    }
+
     pub fn load(&mut self, data: &[u8]) {
         let elf = ElfFile::new(data).unwrap();
         self.entry_point = PROCCESS_ADDR + elf.header.pt2.entry_point() as usize;
@@ -69,11 +107,13 @@ impl Process {
 }
 
 impl Thread {
-    pub fn new_current(process: Arc<RwLock<Process>>) -> Self {
+    pub fn new_current(process: Arc<RwLock<Process>>, name: &str) -> Self {
         Self {
-            name: "kernel".to_string(),
+            id: ThreadId::new(),
+            name: name.to_string(),
             process,
             state: ThreadState::running(),
+            sched: SchedEntity::new(Policy::Fair, CpuSet::ALL),
             _stack: Vec::with_capacity(0)
         }
     }
@@ -89,9 +129,29 @@ impl Thread {
         let state = ThreadState::new(process.read().entry_point as u64, STACK_ADDR as u64 + stack.len() as u64);
 
         Self {
+            id: ThreadId::new(),
             name: name.to_string(),
             process,
             state,
+            sched: SchedEntity::new(Policy::Fair, CpuSet::ALL),
+            _stack: stack
+        }
+    }
+
+    pub fn new_kernel(process: Arc<RwLock<Process>>, name: &str, entry: Box<dyn FnOnce() + Send>) -> Self {
+        let stack = vec![0; KERNEL_STACK_SIZE];
+
+        // Align as if the entry point was called
+        let stack_top = (stack.as_ptr() as u64 + stack.len() as u64) & !0xF;
+        let entry = Box::into_raw(Box::new(entry));
+        let state = ThreadState::new_kernel(kernel_thread_start as *const () as u64, stack_top - 8, entry as u64);
+
+        Self {
+            id: ThreadId::new(),
+            name: name.to_string(),
+            process,
+            state,
+            sched: SchedEntity::new(Policy::Fair, CpuSet::ALL),
             _stack: stack
         }
     }
@@ -105,3 +165,9 @@ impl Thread {
         self.state.clone()
     }
 }
+
+extern "C" fn kernel_thread_start(entry: u64) -> ! {
+    let entry = unsafe { Box::from_raw(entry as *mut Box<dyn FnOnce() + Send>) };
+    entry();
+    runtime().scheduler.exit();
+}
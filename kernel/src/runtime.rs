@@ -1,18 +1,20 @@
-use core::any::{Any, TypeId};
-
-use ahash::RandomState;
-
 use alloc::sync::Arc;
 
-use hashbrown::HashMap;
+use core::any::Any;
 
-use spin::{Once, Mutex, RwLock};
+use spin::{Once, Mutex};
 
 use crate::arch::Arch;
+use crate::block::devices::BlockDevices;
 use crate::drivers::i8042::PcKeyboard;
+use crate::drivers::registry::DeviceRegistry;
+use crate::drivers::rtc::CmosRtc;
 use crate::drivers::video::console::Console;
 use crate::drivers::video::fb::FrameBuffer;
+use crate::fs::vfs::Vfs;
 use crate::scheduler::Scheduler;
+use crate::tasks::timer::Timers;
+use crate::time::Clock;
 
 pub static RUNTIME: Once<Runtime> = Once::new();
 
@@ -22,30 +24,32 @@ pub struct Runtime {
     pub system: Arch,
     pub scheduler: Scheduler,
     pub console: Mutex<Console>,
-    pub keyboard: PcKeyboard,
-    resources: RwLock<HashMap<TypeId, Arc<dyn Any + Send + Sync>, RandomState>>
+    pub keyboard: Arc<PcKeyboard>,
+    pub rtc: CmosRtc,
+    pub clock: Clock,
+    pub timers: Timers,
+    pub devices: DeviceRegistry,
+    pub block_devices: BlockDevices,
+    pub vfs: Vfs
 }
 
 impl Runtime {
-    pub fn init(system: Arch, fb: FrameBuffer, kbd: PcKeyboard) -> &'static Self {
+    pub fn init(system: Arch, fb: FrameBuffer, kbd: PcKeyboard, rtc: CmosRtc) -> &'static Self {
         RUNTIME.call_once(|| {
             Runtime {
                 system,
                 scheduler: Scheduler::new(),
                 console: Mutex::new(Console::new(fb)),
-                keyboard: kbd,
-                resources: RwLock::new(HashMap::with_hasher(RandomState::new()))
+                keyboard: Arc::new(kbd),
+                rtc,
+                clock: Clock::new(),
+                timers: Timers::new(),
+                devices: DeviceRegistry::new(),
+                block_devices: BlockDevices::new(),
+                vfs: Vfs::new()
             }
         })
     }
-
-    pub fn register<T: Resource>(&self, resource: Arc<T>) {
-        self.resources.write().insert(TypeId::of::<T>(), resource);
-    }
-
-    pub fn get<T: Resource>(&self) -> Option<Arc<T>> {
-        self.resources.read().get(&TypeId::of::<T>()).map(|r| r.clone().downcast::<T>().unwrap())
-    }
 }
 
 pub fn runtime() -> &'static Runtime {
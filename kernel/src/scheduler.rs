@@ -1,3 +1,13 @@
+use core::cmp::Reverse;
+use core::fmt;
+use core::mem;
+use core::ops::Sub;
+use core::str::FromStr;
+use core::time::Duration;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use alloc::sync::Arc;
@@ -7,37 +17,684 @@ use lock_api::MappedRwLockWriteGuard;
 use spin::{Mutex, RwLock as RawRwLock};
 use spin::lock_api::{RwLockWriteGuard, RwLock};
 
-use crate::arch::system::ThreadContext;
-use crate::process::{Thread, Process};
+use crate::arch::{Arch, ThreadState};
+use crate::arch::system::{System, ThreadContext};
+use crate::process::{Thread, ThreadId, Process};
+
+pub const QUANTUM: Duration = Duration::from_millis(10);
+
+const BSP_ID: u32 = 0;
+
+const NICE_MIN: i8 = -20;
+const NICE_MAX: i8 = 19;
+const NICE_0_WEIGHT: u64 = 1024;
+
+// Every nice level is worth about 10% CPU time, same as Linux
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291,
+    29154, 23254, 18705, 14949, 11916,
+    9548, 7620, 6100, 4904, 3906,
+    3121, 2501, 1991, 1586, 1277,
+    1024, 820, 655, 526, 423,
+    335, 272, 215, 172, 137,
+    110, 87, 70, 56, 45,
+    36, 29, 23, 18, 15
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Fixed priority from 1 to 99, always runs before fair threads
+    RealTime(u8),
+    Fair,
+    /// Only runs when nothing else wants the CPU
+    Idle
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuSet(u64);
+
+/// What the current thread of a CPU is spending its time on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    User,
+    Kernel,
+    Idle
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuTimes {
+    pub user: Duration,
+    pub kernel: Duration,
+    pub irq: Duration,
+    pub idle: Duration
+}
+
+#[derive(Debug, Clone)]
+pub struct CpuStats {
+    pub id: u32,
+    pub times: CpuTimes,
+    pub switches: u64,
+    // Interrupt time not yet subtracted from the running thread
+    irq_pending: Duration
+}
+
+#[derive(Clone)]
+pub struct SchedEntity {
+    pub policy: Policy,
+    pub nice: i8,
+    pub affinity: CpuSet,
+    // Runtime in nanoseconds weighted by nice value, used by the fair class
+    vruntime: u64,
+    // CPU that per-CPU threads, like the idle threads and the executor, have to stay on
+    home_cpu: Option<u32>,
+    idle_thread: bool,
+    cpu: Option<u32>,
+    last_cpu: u32,
+    waiting: bool,
+    exited: bool,
+    started: Duration,
+    times: CpuTimes,
+    switches: u64,
+    mode: Mode,
+    accounted: Duration
+}
+
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: String,
+    pub policy: Policy,
+    pub nice: i8,
+    pub affinity: CpuSet,
+    pub cpu: Option<u32>,
+    pub times: CpuTimes,
+    pub switches: u64
+}
+
+pub struct Snapshot {
+    pub time: Duration,
+    pub cpus: Vec<CpuStats>,
+    pub threads: Vec<ThreadInfo>
+}
+
+/// CPU statistics as passed to userspace
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct RawCpuStats {
+    pub id: u64,
+    pub user: u64,
+    pub kernel: u64,
+    pub irq: u64,
+    pub idle: u64,
+    pub switches: u64
+}
+
+/// Thread statistics as passed to userspace, `cpu` is -1 when not running
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct RawThreadStats {
+    pub id: u64,
+    pub cpu: i64,
+    pub user: u64,
+    pub kernel: u64,
+    pub switches: u64
+}
 
 pub struct Scheduler {
-    pub threads: RwLock<Vec<Thread>>,
-    cur_thread: Mutex<usize>,
+    threads: RwLock<Vec<Thread>>,
+    cpus: Mutex<BTreeMap<u32, CpuStats>>,
+    kernel_process: Arc<RawRwLock<Process>>
+}
+
+impl Policy {
+    // Higher ranks are scheduled first
+    fn rank(&self) -> u8 {
+        match self {
+            Policy::RealTime(priority) => 1 + priority,
+            Policy::Fair => 1,
+            Policy::Idle => 0
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Policy::RealTime(priority) => write!(f, "rt:{}", priority),
+            Policy::Fair => write!(f, "fair"),
+            Policy::Idle => write!(f, "idle")
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fair" => Ok(Policy::Fair),
+            "idle" => Ok(Policy::Idle),
+            _ => {
+                let priority = s.strip_prefix("rt:").ok_or("Unknown policy")?;
+                let priority = priority.parse().map_err(|_| "Invalid priority")?;
+                Ok(Policy::RealTime(priority))
+            }
+        }
+    }
+}
+
+impl CpuTimes {
+    fn add(&mut self, mode: Mode, time: Duration) {
+        match mode {
+            Mode::User => self.user += time,
+            Mode::Kernel => self.kernel += time,
+            Mode::Idle => self.idle += time
+        }
+    }
+}
+
+impl Sub for CpuTimes {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self {
+            user: self.user.saturating_sub(rhs.user),
+            kernel: self.kernel.saturating_sub(rhs.kernel),
+            irq: self.irq.saturating_sub(rhs.irq),
+            idle: self.idle.saturating_sub(rhs.idle)
+        }
+    }
+}
+
+impl CpuStats {
+    fn new(id: u32) -> Self {
+        Self {
+            id,
+            times: CpuTimes::default(),
+            switches: 0,
+            irq_pending: Duration::ZERO
+        }
+    }
+}
+
+impl From<&CpuStats> for RawCpuStats {
+    fn from(stats: &CpuStats) -> Self {
+        Self {
+            id: stats.id as u64,
+            user: stats.times.user.as_nanos() as u64,
+            kernel: stats.times.kernel.as_nanos() as u64,
+            irq: stats.times.irq.as_nanos() as u64,
+            idle: stats.times.idle.as_nanos() as u64,
+            switches: stats.switches
+        }
+    }
+}
+
+impl From<&ThreadInfo> for RawThreadStats {
+    fn from(info: &ThreadInfo) -> Self {
+        Self {
+            id: info.id.0,
+            cpu: info.cpu.map_or(-1, |cpu| cpu as i64),
+            user: info.times.user.as_nanos() as u64,
+            kernel: info.times.kernel.as_nanos() as u64,
+            switches: info.switches
+        }
+    }
+}
+
+impl CpuSet {
+    pub const ALL: Self = Self(u64::MAX);
+
+    pub fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub fn single(cpu: u32) -> Self {
+        Self(1 << cpu)
+    }
+
+    pub fn contains(&self, cpu: u32) -> bool {
+        cpu < u64::BITS && self.0 & (1 << cpu) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..u64::BITS).filter(|cpu| self.contains(*cpu))
+    }
+}
+
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == Self::ALL {
+            return write!(f, "all");
+        }
+
+        let mut first = true;
+        let mut cpu = 0;
+        while cpu < u64::BITS {
+            if !self.contains(cpu) {
+                cpu += 1;
+                continue;
+            }
+
+            let start = cpu;
+            while self.contains(cpu + 1) {
+                cpu += 1;
+            }
+
+            let separator = if first { "" } else { "," };
+            match cpu == start {
+                true => write!(f, "{}{}", separator, start)?,
+                false => write!(f, "{}{}-{}", separator, start, cpu)?
+            }
+            first = false;
+            cpu += 1;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CpuSet {
+    type Err = &'static str;
+
+    /// Parse a list like `0,2-3` or `all`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "all" {
+            return Ok(Self::ALL);
+        }
+
+        let mut bits = 0u64;
+        for range in s.split(',') {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let start: u32 = start.parse().map_err(|_| "Invalid CPU")?;
+            let end: u32 = end.parse().map_err(|_| "Invalid CPU")?;
+            if start > end || end >= u64::BITS {
+                return Err("Invalid CPU range");
+            }
+            for cpu in start..=end {
+                bits |= 1 << cpu;
+            }
+        }
+        Ok(Self(bits))
+    }
+}
+
+impl SchedEntity {
+    pub fn new(policy: Policy, affinity: CpuSet) -> Self {
+        Self {
+            policy,
+            nice: 0,
+            affinity,
+            vruntime: 0,
+            home_cpu: None,
+            idle_thread: false,
+            cpu: None,
+            last_cpu: 0,
+            waiting: false,
+            exited: false,
+            started: Duration::ZERO,
+            times: CpuTimes::default(),
+            switches: 0,
+            mode: Mode::Kernel,
+            accounted: Duration::ZERO
+        }
+    }
+
+    fn weight(&self) -> u64 {
+        NICE_TO_WEIGHT[(self.nice - NICE_MIN) as usize]
+    }
+
+    fn runnable_on(&self, cpu: u32) -> bool {
+        !self.waiting && self.available_on(cpu)
+    }
+
+    fn available_on(&self, cpu: u32) -> bool {
+        !self.exited && self.cpu.is_none() && self.affinity.contains(cpu)
+    }
+
+    // Threads with the lowest key are picked first, equal real-time threads take turns
+    fn key(&self) -> (Reverse<u8>, u64, Duration) {
+        let vruntime = if self.policy == Policy::Fair { self.vruntime } else { 0 };
+        (Reverse(self.policy.rank()), vruntime, self.started)
+    }
+
+    fn start(&mut self, cpu: u32, now: Duration, mode: Mode) {
+        self.cpu = Some(cpu);
+        self.started = now;
+        self.accounted = now;
+        self.mode = mode;
+    }
+
+    fn stop(&mut self, now: Duration, stats: &mut CpuStats) {
+        self.account(now, stats);
+
+        let runtime = now.saturating_sub(self.started).as_nanos() as u64;
+        self.vruntime += runtime * NICE_0_WEIGHT / self.weight();
+        self.last_cpu = self.cpu.take().unwrap_or(self.last_cpu);
+    }
+
+    // Charge the time since the last call to the current mode, except for the time spent on interrupts
+    fn account(&mut self, now: Duration, stats: &mut CpuStats) {
+        let elapsed = now.saturating_sub(self.accounted).saturating_sub(mem::take(&mut stats.irq_pending));
+        self.times.add(self.mode, elapsed);
+        stats.times.add(self.mode, elapsed);
+        self.accounted = now;
+    }
 }
 
 impl Scheduler {
     pub fn new() -> Self {
         let kernel_process = Arc::new(RawRwLock::new(Process::empty()));
-        let kernel_thread = Thread::new_current(kernel_process);
+
+        // The boot thread runs the async executor and stays on the bootstrap processor
+        let mut kernel_thread = Thread::new_current(kernel_process.clone(), "kernel");
+        kernel_thread.sched.affinity = CpuSet::single(BSP_ID);
+        kernel_thread.sched.home_cpu = Some(BSP_ID);
+        kernel_thread.sched.start(BSP_ID, Duration::ZERO, Mode::Kernel);
+
         Self {
             threads: RwLock::new(vec![kernel_thread]),
-            cur_thread: Mutex::new(0)
+            cpus: Mutex::new(BTreeMap::from([(BSP_ID, CpuStats::new(BSP_ID))])),
+            kernel_process
         }
     }
 
+    /// Register the thread the calling CPU is running on as its idle thread
+    pub fn add_idle_thread(&self) {
+        let cpu = Arch::cpu_id();
+        let mut thread = Thread::new_current(self.kernel_process.clone(), "idle");
+        thread.sched = SchedEntity::new(Policy::Idle, CpuSet::single(cpu));
+        thread.sched.home_cpu = Some(cpu);
+        thread.sched.idle_thread = true;
+        thread.sched.start(cpu, Arch::monotonic_time(), Mode::Kernel);
+
+        Arch::without_interrupts(|| {
+            self.threads.write().push(thread);
+            self.cpus.lock().insert(cpu, CpuStats::new(cpu));
+        });
+    }
+
+    pub fn add(&self, mut thread: Thread) {
+        let cpus = Arch::without_interrupts(|| {
+            let mut threads = self.threads.write();
+            thread.sched.vruntime = min_vruntime(&threads);
+            threads.push(thread);
+            self.preempted_cpus(&threads, threads.len() - 1)
+        });
+        Arch::reschedule(cpus);
+    }
+
+    /// Run `entry` in a new kernel mode thread.
+    pub fn spawn_kernel_thread(&self, entry: impl FnOnce() + Send + 'static, name: &str) -> ThreadId {
+        let thread = Thread::new_kernel(self.kernel_process.clone(), name, Box::new(entry));
+        let id = thread.id;
+        self.add(thread);
+        id
+    }
+
+    /// Stop the current thread, its resources are released after switching away.
+    pub fn exit(&self) -> ! {
+        let cpu = Arch::cpu_id();
+        Arch::without_interrupts(|| {
+            if let Some(thread) = self.threads.write().iter_mut().find(|t| t.sched.cpu == Some(cpu)) {
+                thread.sched.exited = true;
+            }
+        });
+
+        loop {
+            Arch::yield_now();
+        }
+    }
+
+    /// Process of the kernel threads and of the tasks on the executor, like the shell.
+    pub fn kernel_process(&self) -> Arc<RawRwLock<Process>> {
+        self.kernel_process.clone()
+    }
+
+    pub fn current_id(&self) -> ThreadId {
+        let cpu = Arch::cpu_id();
+        Arch::without_interrupts(|| self.threads.read().iter().find(|t| t.sched.cpu == Some(cpu)).unwrap().id)
+    }
+
     pub fn get_current_context<'a>(&self) -> MappedRwLockWriteGuard<'_, RawRwLock<()>, Thread> {
-        let cur_thread = self.cur_thread.lock();
-        RwLockWriteGuard::map(self.threads.write(), |f: &mut Vec<Thread>| &mut f[*cur_thread])
+        let cpu = Arch::cpu_id();
+        RwLockWriteGuard::map(self.threads.write(), |threads: &mut Vec<Thread>| threads.iter_mut().find(|t| t.sched.cpu == Some(cpu)).unwrap())
+    }
+
+    pub fn list(&self) -> Vec<ThreadInfo> {
+        self.snapshot().threads
+    }
+
+    /// Statistics of all CPUs and threads, including the time of the currently running threads
+    pub fn snapshot(&self) -> Snapshot {
+        Arch::without_interrupts(|| {
+            let now = Arch::monotonic_time();
+            let threads = self.threads.read();
+            let mut cpus = self.cpus.lock().clone();
+
+            let threads = threads.iter().map(|t| {
+                let mut sched = t.sched.clone();
+                if let Some(stats) = t.sched.cpu.and_then(|cpu| cpus.get_mut(&cpu)) {
+                    sched.account(now, stats);
+                }
+
+                ThreadInfo {
+                    id: t.id,
+                    name: t.name.clone(),
+                    policy: sched.policy,
+                    nice: sched.nice,
+                    affinity: sched.affinity,
+                    cpu: sched.cpu,
+                    times: sched.times,
+                    switches: sched.switches
+                }
+            }).collect();
+
+            Snapshot {
+                time: now,
+                cpus: cpus.into_values().collect(),
+                threads
+            }
+        })
+    }
+
+    /// Change what the current thread is doing for time accounting
+    pub fn set_mode(&self, mode: Mode) {
+        let cpu = Arch::cpu_id();
+        Arch::without_interrupts(|| {
+            let now = Arch::monotonic_time();
+            let mut threads = self.threads.write();
+            let mut cpus = self.cpus.lock();
+            if let (Some(thread), Some(stats)) = (threads.iter_mut().find(|t| t.sched.cpu == Some(cpu)), cpus.get_mut(&cpu)) {
+                thread.sched.account(now, stats);
+                thread.sched.mode = mode;
+            }
+        });
     }
 
-    pub unsafe fn run_next(&self) -> ! {
-        let tasks_len = self.threads.read().len();
-        let state = {
-            let mut cur_task = self.cur_thread.lock();
-            *cur_task = (*cur_task + 1) % tasks_len;
-            let thread = &self.threads.read()[*cur_task];
-            thread.activate()
+    /// Charge time spent in an interrupt handler to the current CPU
+    pub fn account_irq(&self, time: Duration) {
+        let cpu = Arch::cpu_id();
+        Arch::without_interrupts(|| {
+            if let Some(stats) = self.cpus.lock().get_mut(&cpu) {
+                stats.times.irq += time;
+                stats.irq_pending += time;
+            }
+        });
+    }
+
+    /// Change the policy of a thread, `None` selects the current thread
+    pub fn set_policy(&self, id: Option<ThreadId>, policy: Policy) -> Result<(), &'static str> {
+        if let Policy::RealTime(priority) = policy {
+            if !(1..=99).contains(&priority) {
+                return Err("Priority must be between 1 and 99");
+            }
+        }
+
+        self.update(id, |sched| {
+            sched.policy = policy;
+            Ok(())
+        })
+    }
+
+    pub fn set_nice(&self, id: Option<ThreadId>, nice: i8) -> Result<(), &'static str> {
+        if !(NICE_MIN..=NICE_MAX).contains(&nice) {
+            return Err("Nice value must be between -20 and 19");
+        }
+
+        self.update(id, |sched| {
+            sched.nice = nice;
+            Ok(())
+        })
+    }
+
+    pub fn set_affinity(&self, id: Option<ThreadId>, affinity: CpuSet) -> Result<(), &'static str> {
+        if affinity.is_empty() {
+            return Err("Affinity must contain a CPU");
+        }
+
+        self.update(id, |sched| {
+            if sched.home_cpu.is_some_and(|cpu| !affinity.contains(cpu)) {
+                return Err("Per-CPU threads can't leave their CPU");
+            }
+            sched.affinity = affinity;
+            Ok(())
+        })
+    }
+
+    fn update(&self, id: Option<ThreadId>, f: impl FnOnce(&mut SchedEntity) -> Result<(), &'static str>) -> Result<(), &'static str> {
+        let cpu = Arch::cpu_id();
+        let cpus = Arch::without_interrupts(|| {
+            let mut threads = self.threads.write();
+            let index = match id {
+                Some(id) => threads.iter().position(|t| t.id == id),
+                None => threads.iter().position(|t| t.sched.cpu == Some(cpu))
+            }.ok_or("Thread not found")?;
+
+            // Every CPU needs its idle thread to fall back to
+            if threads[index].sched.idle_thread {
+                return Err("Idle threads can't be changed");
+            }
+            f(&mut threads[index].sched)?;
+            Ok(self.preempted_cpus(&threads, index))
+        })?;
+
+        // Let the affected CPUs reconsider their choice with the new parameters
+        Arch::reschedule(cpus);
+        Ok(())
+    }
+
+    // CPUs that should pick a new thread after the thread at `index` became runnable or changed
+    fn preempted_cpus(&self, threads: &[Thread], index: usize) -> CpuSet {
+        let sched = &threads[index].sched;
+        // A running thread may no longer be the best choice for its CPU
+        if let Some(cpu) = sched.cpu {
+            return CpuSet::single(cpu);
+        }
+        if sched.waiting || sched.exited {
+            return CpuSet(0);
+        }
+
+        // Only CPUs that are idle or run less important threads
+        let rank = sched.policy.rank();
+        let bits = self.cpus.lock().keys()
+            .filter(|cpu| sched.affinity.contains(**cpu))
+            .filter(|cpu| !threads.iter().any(|t| {
+                t.sched.cpu == Some(**cpu) && !t.sched.idle_thread && !t.sched.waiting && t.sched.policy.rank() >= rank
+            }))
+            .fold(0, |bits, cpu| bits | 1 << cpu);
+        CpuSet(bits)
+    }
+
+    /// Called by the current thread with interrupts disabled when it runs out of work, returns whether it should give up the CPU.
+    /// Except for idle threads, the thread won't be scheduled again until it's woken.
+    pub fn idle(&self, pending: impl Fn() -> bool) -> bool {
+        let cpu = Arch::cpu_id();
+        let mut threads = self.threads.write();
+        let Some(current) = threads.iter().position(|t| t.sched.cpu == Some(cpu)) else {
+            return false;
         };
-        state.activate();
+
+        let idle = threads[current].sched.policy == Policy::Idle;
+        let others = threads.iter().any(|t| t.sched.runnable_on(cpu) && (!idle || t.sched.policy != Policy::Idle));
+
+        // Checked while holding the lock so a concurrent wake can't be missed
+        if !others || pending() {
+            return false;
+        }
+
+        threads[current].sched.waiting = !idle;
+        true
+    }
+
+    pub fn wake(&self, id: ThreadId) {
+        let cpus = Arch::without_interrupts(|| {
+            let mut threads = self.threads.write();
+            let min = min_vruntime(&threads);
+            let index = threads.iter().position(|t| t.id == id && t.sched.waiting)?;
+
+            let sched = &mut threads[index].sched;
+            sched.waiting = false;
+            // Sleeping doesn't build up credit beyond a single quantum
+            sched.vruntime = sched.vruntime.max(min.saturating_sub(QUANTUM.as_nanos() as u64));
+            Some(self.preempted_cpus(&threads, index))
+        });
+
+        if let Some(cpus) = cpus {
+            Arch::reschedule(cpus);
+        }
+    }
+
+    /// Whether other threads are waiting for the CPU
+    pub fn needs_preemption(&self) -> bool {
+        let cpu = Arch::cpu_id();
+        let threads = self.threads.read();
+        let rank = threads.iter().find(|t| t.sched.cpu == Some(cpu)).map_or(0, |t| t.sched.policy.rank());
+        threads.iter().any(|t| t.sched.runnable_on(cpu) && t.sched.policy.rank() >= rank)
     }
+
+    /// Select the next thread for this CPU, must be called with interrupts disabled.
+    pub fn schedule(&self) -> ThreadState {
+        let cpu = Arch::cpu_id();
+        let now = Arch::monotonic_time();
+        let mut threads = self.threads.write();
+        let mut cpus = self.cpus.lock();
+        let stats = cpus.get_mut(&cpu).expect("CPU not registered");
+
+        // Threads that exited on this CPU are no longer using their stack
+        threads.retain(|t| !(t.sched.exited && t.sched.cpu.is_none() && t.sched.last_cpu == cpu));
+
+        let current = threads.iter().position(|t| t.sched.cpu == Some(cpu));
+        if let Some(current) = current {
+            threads[current].sched.stop(now, stats);
+        }
+
+        let next = threads.iter()
+            .enumerate()
+            .filter(|(_, t)| t.sched.runnable_on(cpu))
+            .min_by_key(|(_, t)| t.sched.key())
+            .map(|(i, _)| i)
+            // Fall back to waiting threads, which will check for work themselves
+            .or_else(|| threads.iter().position(|t| t.sched.available_on(cpu)))
+            .expect("No thread to run");
+
+        if Some(next) != current {
+            threads[next].sched.switches += 1;
+            stats.switches += 1;
+        }
+
+        let mode = if threads[next].state.is_user() { Mode::User } else { Mode::Kernel };
+        threads[next].sched.start(cpu, now, mode);
+        threads[next].activate()
+    }
+}
+
+fn min_vruntime(threads: &[Thread]) -> u64 {
+    threads.iter()
+        .filter(|t| t.sched.policy == Policy::Fair && !t.sched.waiting && !t.sched.exited)
+        .map(|t| t.sched.vruntime)
+        .min()
+        .unwrap_or(0)
 }
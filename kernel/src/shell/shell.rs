@@ -1,26 +1,39 @@
-use alloc::string::String;
+use alloc::format;
+use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
 
+use core::cmp::Reverse;
 use core::fmt::Write;
-use core::future;
+use core::str::FromStr;
+use core::time::Duration;
 
 use futures_util::StreamExt;
+use futures_util::future::{select, Either};
 
 use humansize::SizeFormatter;
 
 use spin::RwLock;
 
 use crate::ALLOCATOR;
+use crate::arch::Arch;
+use crate::arch::system::System;
 use crate::block::Block;
+use crate::block::cache::CACHE_SECTORS;
+use crate::block::gpt::Gpt;
+use crate::block::loopdev::LoopDevice;
 use crate::block::mbr::Mbr;
-use crate::drivers::block::virtio_blk::VirtioBlk;
+use crate::block::ramdisk::RamDisk;
 use crate::drivers::i8042::KeyboardStream;
-use crate::fs::FileSystem;
-use crate::fs::vfat::VFat16;
-use crate::process::{Process, Thread};
+use crate::drivers::registry::DeviceClass;
+use crate::fs::tmpfs::Tmpfs;
+use crate::fs::{self, FileType, FsType, SeekFrom};
+use crate::process::{Process, Thread, ThreadId};
 use crate::runtime::runtime;
+use crate::scheduler::{CpuSet, CpuTimes, Policy};
+use crate::tasks::timer;
+use crate::time::DateTime;
 
 async fn read_line(kbd: &mut KeyboardStream<'_>) -> String {
     let mut input = String::with_capacity(16);
@@ -39,113 +52,617 @@ pub async fn ios_shell() {
     let mut stream = runtime().keyboard.stream();
 
     loop {
-        runtime().console.lock().write_str("> ").unwrap();
+        write!(runtime().console.lock(), "{}> ", cwd()).unwrap();
         let input = read_line(&mut stream).await;
         let (cmd, args) = input.split_once(' ').unwrap_or((&input, ""));
         match cmd {
-            "echo" => writeln!(runtime().console.lock(), "{}", args).unwrap(),
+            "echo" => echo(args).await,
             "read" => read(args).await,
-            "part" => part().await,
-            "ls" => ls().await,
+            "write" => write(args).await,
+            "sync" => sync().await,
+            "cache" => cache(),
+            "part" => part(args).await,
+            "lsblk" => lsblk(),
+            "devices" => devices(args),
+            "eject" => eject(args).await,
+            "ramdisk" => ramdisk(args),
+            "losetup" => losetup(args).await,
+            "mounts" => mounts(),
+            "ls" => ls(args).await,
+            "cd" => cd(args).await,
+            "pwd" => pwd(),
+            "mount" => mount(args).await,
+            "umount" => umount(args).await,
+            "stat" => stat(args).await,
             "cat" => cat(args).await,
+            "touch" => touch(args).await,
+            "mkdir" => mkdir(args).await,
+            "rm" => rm(args).await,
+            "mv" => mv(args).await,
             "process" => process(args).await,
             "ps" => ps().await,
+            "top" => top(&mut stream).await,
             "mem" => mem(),
+            "date" => date(),
+            "sleep" => sleep(args).await,
+            "nice" => nice(args),
+            "chrt" => chrt(args),
+            "taskset" => taskset(args),
+            "burn" => burn(args),
             _ => writeln!(runtime().console.lock(), "Command '{}' not found", cmd).unwrap(),
         }
     }
 }
 
 pub async fn read(args: &str) {
-    let args: Vec<&str> = args.split(' ').collect();
+    let Some((device, sector)) = args.split_once(' ').and_then(|(device, sector)| Some((device, sector.parse().ok()?))) else {
+        writeln!(runtime().console.lock(), "Usage: read <device> <sector>").unwrap();
+        return;
+    };
+    let Some(block) = block_device(device) else {
+        return;
+    };
 
-    let block = runtime().get::<VirtioBlk>().unwrap();
     let mut buf = [1u8; 512];
-    block.read(buf.as_mut_slice(), args[0].parse().unwrap()).await.unwrap();
+    match block.read(buf.as_mut_slice(), sector).await {
+        Ok(()) => writeln!(runtime().console.lock(), "Return: {:x?}", &buf).unwrap(),
+        Err(err) => writeln!(runtime().console.lock(), "Read failed: {}", err).unwrap()
+    }
+}
+
+pub async fn write(args: &str) {
+    let mut args = args.splitn(3, ' ');
+    let (Some(device), Some(Ok(sector)), Some(text)) = (args.next(), args.next().map(str::parse), args.next()) else {
+        writeln!(runtime().console.lock(), "Usage: write <device> <sector> <text>").unwrap();
+        return;
+    };
+    let Some(block) = block_device(device) else {
+        return;
+    };
+
+    let mut buf = [0u8; 512];
+    let len = text.len().min(buf.len());
+    buf[..len].copy_from_slice(&text.as_bytes()[..len]);
 
-    writeln!(runtime().console.lock(), "Return: {:x?}", &buf).unwrap();
+    if let Err(err) = block.write(&buf, sector).await {
+        writeln!(runtime().console.lock(), "Write failed: {}", err).unwrap();
+    }
 }
 
-pub async fn part() {
-    let block = runtime().get::<VirtioBlk>().unwrap();
+pub async fn sync() {
+    // Filesystems write their metadata through the caches, which are flushed afterwards
+    for mount in runtime().vfs.list() {
+        if let Err(err) = mount.fs.sync().await {
+            writeln!(runtime().console.lock(), "{}: sync failed: {}", mount.path, err).unwrap();
+        }
+    }
 
-    let mbr = Mbr::new(block).await.unwrap();
-    mbr.partitions().iter().enumerate().for_each({
-        |(i, p)| writeln!(runtime().console.lock(), "Partition {}: {:?}", i, p).unwrap()
-    })
+    for (name, cache) in runtime().block_devices.caches() {
+        if let Err(err) = cache.flush().await {
+            writeln!(runtime().console.lock(), "{}: flush failed: {}", name, err).unwrap();
+        }
+    }
 }
 
-pub async fn ls() {
-    let block = runtime().get::<VirtioBlk>().unwrap();
+pub fn cache() {
+    for (name, cache) in runtime().block_devices.caches() {
+        let stats = cache.stats();
+        let lookups = (stats.hits + stats.misses).max(1);
+        writeln!(runtime().console.lock(), "{}: {}/{} sectors, {} dirty", name, stats.entries, CACHE_SECTORS, stats.dirty).unwrap();
+        writeln!(runtime().console.lock(), "  Hits: {} Misses: {} ({}% hit rate)", stats.hits, stats.misses, stats.hits * 100 / lookups).unwrap();
+        writeln!(runtime().console.lock(), "  Read ahead: {} Written back: {}", stats.read_ahead, stats.written_back).unwrap();
+    }
+}
 
-    let mbr = Mbr::new(block).await.unwrap();
-    let partition = mbr.get_partition(0).await.unwrap();
+pub fn lsblk() {
+    for info in runtime().devices.list(Some(DeviceClass::Block)) {
+        let Some(block) = info.block() else {
+            continue;
+        };
 
-    let fs = VFat16::new(Arc::new(partition)).await.unwrap();
-    let mut entries = fs.listdir("/").await.unwrap();
+        // Partitions are listed after their disk as names sort that way
+        let name = match info.parent {
+            Some(_) => format!("  {}", info.name),
+            None => info.name
+        };
+        let size = block.sector_count() * block.sector_size() as u64;
+        writeln!(runtime().console.lock(), "{:<8} {:>10} sectors {:>10} block size {}{}", name, block.sector_count(), SizeFormatter::new(size, humansize::DECIMAL).to_string(), block.block_size(), if block.read_only() { " read-only" } else { "" }).unwrap();
+    }
+}
+
+pub async fn eject(args: &str) {
+    let Some(block) = block_device(args) else {
+        return;
+    };
+
+    // Mark filesystems on the device and its partitions as cleanly unmounted
+    for mount in runtime().vfs.list() {
+        let device = runtime().devices.get(DeviceClass::Block, &mount.device);
+        if mount.device == args || device.and_then(|info| info.parent).as_deref() == Some(args) {
+            if let Err(err) = mount.fs.sync().await {
+                writeln!(runtime().console.lock(), "{}: sync failed: {}", mount.path, err).unwrap();
+            }
+        }
+    }
 
-    while let Some(entry) = entries.next().await {
-        writeln!(runtime().console.lock(), "{:?}", entry).unwrap();
+    if let Err(err) = block.flush().await.and_then(|_| runtime().devices.remove(DeviceClass::Block, args)) {
+        writeln!(runtime().console.lock(), "Eject failed: {}", err).unwrap();
     }
 }
 
-pub async fn cat(args: &str) {
-    let block = runtime().get::<VirtioBlk>().unwrap();
+pub fn ramdisk(args: &str) {
+    let Ok(kilobytes) = args.parse::<usize>() else {
+        writeln!(runtime().console.lock(), "Usage: ramdisk <kilobytes>").unwrap();
+        return;
+    };
+
+    // ram0 is reserved for the image loaded by the bootloader
+    let name = free_block_name("ram", 1);
+    let result = kilobytes.checked_mul(1024).ok_or("Size too large")
+        .and_then(RamDisk::new)
+        .and_then(|disk| runtime().block_devices.add_disk(&name, Arc::new(disk)));
+    match result {
+        Ok(()) => writeln!(runtime().console.lock(), "Created {}", name).unwrap(),
+        Err(err) => writeln!(runtime().console.lock(), "Creating RAM disk failed: {}", err).unwrap()
+    }
+}
+
+/// Attach a file of the root filesystem as a block device, which gets scanned and mounted like a disk.
+pub async fn losetup(args: &str) {
+    if args.is_empty() {
+        writeln!(runtime().console.lock(), "Usage: losetup <file>").unwrap();
+        return;
+    }
+
+    let name = free_block_name("loop", 0);
+    let result = match runtime().vfs.open(&path(args)).await {
+        Ok(file) => runtime().block_devices.add_disk(&name, Arc::new(LoopDevice::new(file))),
+        Err(err) => Err(err.as_str())
+    };
+    match result {
+        Ok(()) => writeln!(runtime().console.lock(), "Attached {} to {}", args, name).unwrap(),
+        Err(err) => writeln!(runtime().console.lock(), "losetup failed: {}", err).unwrap()
+    }
+}
 
-    let mbr = Mbr::new(block).await.unwrap();
-    let partition = mbr.get_partition(0).await.unwrap();
+// First unused block device name of the prefix followed by a number
+fn free_block_name(prefix: &str, first: usize) -> String {
+    (first..).map(|i| format!("{}{}", prefix, i)).find(|name| runtime().devices.get(DeviceClass::Block, name).is_none()).unwrap()
+}
+
+pub fn devices(args: &str) {
+    let class = match args {
+        "" => None,
+        class => match class.parse() {
+            Ok(class) => Some(class),
+            Err(err) => {
+                writeln!(runtime().console.lock(), "{}", err).unwrap();
+                return;
+            }
+        }
+    };
 
-    let fs = Arc::new(VFat16::new(Arc::new(partition)).await.unwrap());
-    let entries = fs.listdir("/").await.unwrap();
-    let entry = entries.filter(|e| future::ready(e.name == args)).next().await.unwrap();
+    for info in runtime().devices.list(class) {
+        match &info.parent {
+            Some(parent) => writeln!(runtime().console.lock(), "{:<8} {} (part of {})", info.class(), info.name, parent).unwrap(),
+            None => writeln!(runtime().console.lock(), "{:<8} {}", info.class(), info.name).unwrap()
+        }
+    }
+}
+
+pub fn mounts() {
+    for mount in runtime().vfs.list() {
+        match mount.fs.free_space() {
+            Some(free) => writeln!(runtime().console.lock(), "{} on {} type {} ({} free)", mount.device, mount.path, mount.fs_type, SizeFormatter::new(free, humansize::DECIMAL)).unwrap(),
+            None => writeln!(runtime().console.lock(), "{} on {} type {}", mount.device, mount.path, mount.fs_type).unwrap()
+        }
+    }
+}
+
+pub async fn part(args: &str) {
+    let Some(block) = block_device(if args.is_empty() { "vda" } else { args }) else {
+        return;
+    };
+
+    let mbr = match Mbr::new(block.clone()).await {
+        Ok(mbr) if mbr.is_valid() => mbr,
+        Ok(_) => {
+            writeln!(runtime().console.lock(), "No partition table").unwrap();
+            return;
+        },
+        Err(err) => {
+            writeln!(runtime().console.lock(), "Read failed: {}", err).unwrap();
+            return;
+        }
+    };
+
+    if !mbr.is_protective() {
+        for (i, entry) in mbr.partitions().iter().enumerate().filter(|(_, entry)| !entry.is_empty()) {
+            let size = entry.size() * block.sector_size() as u64;
+            writeln!(runtime().console.lock(), "Partition {}: type {:#04x}, sectors {}-{}, {}", i, entry.partition_type(), entry.start(), entry.start() + entry.size() - 1, SizeFormatter::new(size, humansize::DECIMAL)).unwrap();
+        }
+        return;
+    }
+
+    let gpt = match Gpt::new(block.clone()).await {
+        Ok(gpt) => gpt,
+        Err(err) => {
+            writeln!(runtime().console.lock(), "Invalid GPT: {}", err).unwrap();
+            return;
+        }
+    };
+
+    writeln!(runtime().console.lock(), "GPT disk {}", gpt.disk_guid()).unwrap();
+    for partition in gpt.partitions() {
+        let size = (partition.last_lba + 1).saturating_sub(partition.first_lba) * block.sector_size() as u64;
+        let type_name = partition.type_guid.type_name().map_or_else(|| partition.type_guid.to_string(), |name| name.to_string());
+        writeln!(runtime().console.lock(), "Partition {}: '{}' {}, sectors {}-{}, {}", partition.index, partition.name, type_name, partition.first_lba, partition.last_lba, SizeFormatter::new(size, humansize::DECIMAL)).unwrap();
+        writeln!(runtime().console.lock(), "  {} attributes {:#x}", partition.unique_guid, partition.attributes).unwrap();
+    }
+}
+
+fn block_device(name: &str) -> Option<Arc<dyn Block>> {
+    let block = runtime().devices.block(name);
+    if block.is_none() {
+        writeln!(runtime().console.lock(), "Device '{}' not found", name).unwrap();
+    }
+    block
+}
+
+fn cwd() -> String {
+    runtime().scheduler.kernel_process().read().cwd().to_string()
+}
+
+// Absolute path of an argument relative to the current directory
+fn path(arg: &str) -> String {
+    fs::vfs::normalize(&cwd(), arg)
+}
+
+pub fn pwd() {
+    writeln!(runtime().console.lock(), "{}", cwd()).unwrap();
+}
+
+pub async fn cd(args: &str) {
+    let path = path(if args.is_empty() { "/" } else { args });
+    match runtime().vfs.file_type(&path).await {
+        // The scheduler reads the process when switching threads, so don't get interrupted holding the lock
+        Ok(FileType::Directory) => Arch::without_interrupts(|| runtime().scheduler.kernel_process().write().set_cwd(path)),
+        Ok(_) => writeln!(runtime().console.lock(), "cd: {}", fs::Error::NotADirectory).unwrap(),
+        Err(err) => writeln!(runtime().console.lock(), "cd: {}", err).unwrap()
+    }
+}
+
+pub async fn mount(args: &str) {
+    let Some((device, target)) = args.split_once(' ') else {
+        writeln!(runtime().console.lock(), "Usage: mount <device|tmpfs> <path>").unwrap();
+        return;
+    };
+
+    if device == "tmpfs" {
+        let fs = Arc::new(Tmpfs::new());
+        if let Err(err) = runtime().vfs.mount(&path(target.trim()), device, FsType::Tmpfs, fs).await {
+            writeln!(runtime().console.lock(), "mount: {}", err).unwrap();
+        }
+        return;
+    }
+
+    let Some(block) = block_device(device) else {
+        return;
+    };
+
+    if runtime().vfs.list().iter().any(|mount| mount.device == device) {
+        writeln!(runtime().console.lock(), "mount: {} is already mounted", device).unwrap();
+        return;
+    }
+
+    let result = match fs::probe(block.as_ref()).await {
+        Ok(Some(fs_type)) => match fs::open(fs_type, block).await {
+            Ok(fs) => runtime().vfs.mount(&path(target.trim()), device, fs_type, fs).await,
+            Err(err) => Err(err)
+        },
+        Ok(None) => {
+            writeln!(runtime().console.lock(), "mount: no known filesystem on {}", device).unwrap();
+            return;
+        },
+        Err(err) => Err(err)
+    };
+    if let Err(err) = result {
+        writeln!(runtime().console.lock(), "mount: {}", err).unwrap();
+    }
+}
+
+pub async fn umount(args: &str) {
+    if let Err(err) = runtime().vfs.unmount(&path(args)).await {
+        writeln!(runtime().console.lock(), "umount: {}", err).unwrap();
+    }
+}
+
+pub async fn ls(args: &str) {
+    let mut dir = match runtime().vfs.open_dir(&path(args)).await {
+        Ok(dir) => dir,
+        Err(err) => {
+            writeln!(runtime().console.lock(), "ls: {}", err).unwrap();
+            return;
+        }
+    };
+
+    loop {
+        match dir.next_entry().await {
+            Ok(Some(entry)) => {
+                let suffix = match entry.metadata.file_type {
+                    FileType::Directory => "/",
+                    FileType::Symlink => "@",
+                    FileType::File => ""
+                };
+                writeln!(runtime().console.lock(), "{:>6} {:>10} {}{}", entry.inode, entry.metadata.size, entry.name, suffix).unwrap();
+            },
+            Ok(None) => break,
+            Err(err) => {
+                writeln!(runtime().console.lock(), "ls: {}", err).unwrap();
+                break;
+            }
+        }
+    }
+}
+
+pub async fn stat(args: &str) {
+    let metadata = match runtime().vfs.stat(&path(args)).await {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            writeln!(runtime().console.lock(), "stat: {}", err).unwrap();
+            return;
+        }
+    };
+
+    let time = |time: Option<DateTime>| time.map_or(String::from("-"), |time| time.to_string());
+    let mut console = runtime().console.lock();
+    writeln!(console, "Type: {} Size: {} Mode: {:04o}", metadata.file_type, metadata.size, metadata.mode).unwrap();
+    writeln!(console, "Created: {}", time(metadata.created)).unwrap();
+    writeln!(console, "Modified: {}", time(metadata.modified)).unwrap();
+    writeln!(console, "Accessed: {}", time(metadata.accessed)).unwrap();
+}
+
+pub async fn cat(args: &str) {
+    let mut file = match runtime().vfs.open(&path(args)).await {
+        Ok(file) => file,
+        Err(err) => {
+            writeln!(runtime().console.lock(), "cat: {}", err).unwrap();
+            return;
+        }
+    };
 
-    let mut file = fs.open_node(entry.inode, entry.length as u64).await.unwrap();
     let mut buf = [0u8; 512];
-    let len = file.read(&mut buf).await.unwrap();
-    runtime().console.lock().write_str(&String::from_utf8_lossy(&buf[0..len])).unwrap();
+    loop {
+        match file.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(len) => runtime().console.lock().write_str(&String::from_utf8_lossy(&buf[0..len])).unwrap(),
+            Err(err) => {
+                writeln!(runtime().console.lock(), "cat: {}", err).unwrap();
+                break;
+            }
+        }
+    }
 }
 
-pub async fn process(args: &str) {
-    let block = runtime().get::<VirtioBlk>().unwrap();
+/// Print the text, or write it to a file with `>` or append it with `>>`.
+pub async fn echo(args: &str) {
+    let (text, target, append) = match args.split_once('>') {
+        Some((text, target)) => match target.strip_prefix('>') {
+            Some(target) => (text.trim_end(), target.trim(), true),
+            None => (text.trim_end(), target.trim(), false)
+        },
+        None => {
+            writeln!(runtime().console.lock(), "{}", args).unwrap();
+            return;
+        }
+    };
+
+    if let Err(err) = write_file(&path(target), format!("{}\n", text).as_bytes(), append).await {
+        writeln!(runtime().console.lock(), "echo: {}", err).unwrap();
+    }
+}
+
+// Write to a file, creating it when missing
+async fn write_file(path: &str, data: &[u8], append: bool) -> fs::Result<()> {
+    let mut file = match runtime().vfs.open(path).await {
+        Err(fs::Error::NotFound) => runtime().vfs.create(path).await?,
+        file => file?
+    };
+
+    match append {
+        true => file.seek(SeekFrom::End(0))?,
+        false => {
+            file.truncate(0).await?;
+            0
+        }
+    };
+    file.write_all(data).await?;
+    file.close().await
+}
+
+pub async fn touch(args: &str) {
+    let path = path(args);
+    if runtime().vfs.file_type(&path).await.is_ok() {
+        return;
+    }
+    if let Err(err) = runtime().vfs.create(&path).await {
+        writeln!(runtime().console.lock(), "touch: {}", err).unwrap();
+    }
+}
+
+pub async fn mkdir(args: &str) {
+    if let Err(err) = runtime().vfs.mkdir(&path(args)).await {
+        writeln!(runtime().console.lock(), "mkdir: {}", err).unwrap();
+    }
+}
 
-    let mbr = Mbr::new(block).await.unwrap();
-    let partition = mbr.get_partition(0).await.unwrap();
+pub async fn rm(args: &str) {
+    if let Err(err) = runtime().vfs.remove(&path(args)).await {
+        writeln!(runtime().console.lock(), "rm: {}", err).unwrap();
+    }
+}
+
+pub async fn mv(args: &str) {
+    let Some((from, to)) = args.split_once(' ') else {
+        writeln!(runtime().console.lock(), "Usage: mv <from> <to>").unwrap();
+        return;
+    };
+
+    if let Err(err) = runtime().vfs.rename(&path(from), &path(to.trim())).await {
+        writeln!(runtime().console.lock(), "mv: {}", err).unwrap();
+    }
+}
 
-    let fs = Arc::new(VFat16::new(Arc::new(partition)).await.unwrap());
-    let entries = fs.listdir("/").await.unwrap();
-    let entry = entries.filter(|e| future::ready(e.name == args)).next().await.unwrap();
+pub async fn process(args: &str) {
+    let mut file = match runtime().vfs.open(&path(args)).await {
+        Ok(file) => file,
+        Err(err) => {
+            writeln!(runtime().console.lock(), "process: {}", err).unwrap();
+            return;
+        }
+    };
 
-    let mut file = fs.open_node(entry.inode, entry.length as u64).await.unwrap();
-    let mut buf = vec![0u8; entry.length as usize];
+    let mut buf = vec![0u8; file.stat().size as usize];
     let mut offset = 0;
-    while offset < entry.length as usize {
-        let len = file.read(&mut buf[offset..]).await.unwrap();
-        if len == 0 {
-            break;
-        } else {
-            offset += len;
+    while offset < buf.len() {
+        match file.read(&mut buf[offset..]).await {
+            Ok(0) => break,
+            Ok(len) => offset += len,
+            Err(err) => {
+                writeln!(runtime().console.lock(), "process: {}", err).unwrap();
+                return;
+            }
         }
     }
 
     let mut process = Process::new();
+    process.set_cwd(cwd());
     process.load(&buf);
     let process = Arc::new(RwLock::new(process));
 
     let thread = Thread::new(process, "process");
-    runtime().scheduler.threads.write().push(thread);
+    runtime().scheduler.add(thread);
 }
 
 pub async fn ps() {
-    let threads = runtime().scheduler.threads.read();
+    let threads = runtime().scheduler.list();
+    let mut console = runtime().console.lock();
+    writeln!(console, "{:>4} {:<10} {:<6} {:>4} {:<8} {}", "ID", "NAME", "POLICY", "NICE", "CPUS", "CPU").unwrap();
     for thread in threads.iter() {
-        writeln!(runtime().console.lock(), "{:?}", thread.name).unwrap();
+        let cpu = thread.cpu.map_or(String::from("-"), |cpu| cpu.to_string());
+        writeln!(console, "{:>4} {:<10} {:<6} {:>4} {:<8} {}", thread.id, thread.name, thread.policy.to_string(), thread.nice, thread.affinity.to_string(), cpu).unwrap();
+    }
+}
+
+pub async fn top(kbd: &mut KeyboardStream<'_>) {
+    const THREADS: usize = 10;
+
+    let mut previous = runtime().scheduler.snapshot();
+    loop {
+        // Refresh every second until a key is pressed
+        if let Either::Right(_) = select(timer::sleep(Duration::from_secs(1)), kbd.next()).await {
+            break;
+        }
+
+        let current = runtime().scheduler.snapshot();
+        let interval = (current.time - previous.time).as_secs_f64();
+        let percentage = |time: Duration| time.as_secs_f64() * 100.0 / interval;
+
+        let mut console = runtime().console.lock();
+        console.clear();
+        writeln!(console, "{:>3} {:>6} {:>6} {:>6} {:>6} {:>8}", "CPU", "USER", "KERNEL", "IRQ", "IDLE", "SWITCHES").unwrap();
+        for cpu in current.cpus.iter() {
+            let Some(prev) = previous.cpus.iter().find(|c| c.id == cpu.id) else {
+                continue;
+            };
+
+            let times = cpu.times - prev.times;
+            writeln!(console, "{:>3} {:>5.1}% {:>5.1}% {:>5.1}% {:>5.1}% {:>8}", cpu.id, percentage(times.user), percentage(times.kernel), percentage(times.irq), percentage(times.idle), cpu.switches - prev.switches).unwrap();
+        }
+
+        let mut threads: Vec<_> = current.threads.iter().map(|thread| {
+            let prev = previous.threads.iter().find(|t| t.id == thread.id);
+            let times = thread.times - prev.map_or(CpuTimes::default(), |t| t.times);
+            (thread, times, thread.switches - prev.map_or(0, |t| t.switches))
+        }).collect();
+        threads.sort_by_key(|(_, times, _)| Reverse(times.user + times.kernel));
+
+        writeln!(console, "\n{:>4} {:<10} {:>6} {:>6} {:>8}", "ID", "NAME", "USER", "KERNEL", "SWITCHES").unwrap();
+        for (thread, times, switches) in threads.iter().take(THREADS) {
+            writeln!(console, "{:>4} {:<10} {:>5.1}% {:>5.1}% {:>8}", thread.id, thread.name, percentage(times.user), percentage(times.kernel), switches).unwrap();
+        }
+
+        drop(console);
+        previous = current;
     }
 }
 
 pub fn mem() {
-    let (used, free) = {
+    let (used, free) = Arch::without_interrupts(|| {
         let allocator = ALLOCATOR.lock();
         (allocator.used(), allocator.free())
-    };
+    });
     writeln!(runtime().console.lock(), "Memory {}/{}", SizeFormatter::new(used, humansize::DECIMAL), SizeFormatter::new(free, humansize::DECIMAL)).unwrap();
 }
+
+pub fn date() {
+    writeln!(runtime().console.lock(), "{} UTC", runtime().clock.now()).unwrap();
+}
+
+pub async fn sleep(args: &str) {
+    let deadline = args.parse().ok().and_then(|ms| Arch::monotonic_time().checked_add(Duration::from_millis(ms)));
+    match deadline {
+        Some(deadline) => timer::sleep_until(deadline).await,
+        None => writeln!(runtime().console.lock(), "Usage: sleep <milliseconds>").unwrap()
+    }
+}
+
+fn parse_thread_args<T: FromStr>(args: &str) -> Option<(ThreadId, T)> {
+    let (id, value) = args.split_once(' ')?;
+    Some((ThreadId(id.parse().ok()?), value.parse().ok()?))
+}
+
+pub fn nice(args: &str) {
+    let result = match parse_thread_args(args) {
+        Some((id, nice)) => runtime().scheduler.set_nice(Some(id), nice),
+        None => Err("Usage: nice <thread> <-20..19>")
+    };
+    if let Err(err) = result {
+        writeln!(runtime().console.lock(), "{}", err).unwrap();
+    }
+}
+
+pub fn chrt(args: &str) {
+    let result = match parse_thread_args::<Policy>(args) {
+        Some((id, policy)) => runtime().scheduler.set_policy(Some(id), policy),
+        None => Err("Usage: chrt <thread> <fair|idle|rt:1..99>")
+    };
+    if let Err(err) = result {
+        writeln!(runtime().console.lock(), "{}", err).unwrap();
+    }
+}
+
+pub fn taskset(args: &str) {
+    let result = match parse_thread_args::<CpuSet>(args) {
+        Some((id, affinity)) => runtime().scheduler.set_affinity(Some(id), affinity),
+        None => Err("Usage: taskset <thread> <cpus>")
+    };
+    if let Err(err) = result {
+        writeln!(runtime().console.lock(), "{}", err).unwrap();
+    }
+}
+
+/// Keep a CPU busy in a kernel thread, useful to try out the scheduler
+pub fn burn(args: &str) {
+    let Some(deadline) = args.parse().ok().and_then(|seconds| Arch::monotonic_time().checked_add(Duration::from_secs(seconds))) else {
+        writeln!(runtime().console.lock(), "Usage: burn <seconds>").unwrap();
+        return;
+    };
+
+    let id = runtime().scheduler.spawn_kernel_thread(move || {
+        while Arch::monotonic_time() < deadline {
+            core::hint::spin_loop();
+        }
+    }, "burn");
+    writeln!(runtime().console.lock(), "Started thread {}", id).unwrap();
+}
@@ -8,10 +8,14 @@ use core::task::{Context, Poll, Waker};
 
 use crossbeam_queue::ArrayQueue;
 
+use crate::process::ThreadId;
+use crate::runtime::runtime;
+
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
     task_queue: Arc<ArrayQueue<TaskId>>,
     waker_cache: BTreeMap<TaskId, Waker>,
+    thread: ThreadId,
 }
 
 impl Executor {
@@ -20,6 +24,7 @@ impl Executor {
             tasks: BTreeMap::new(),
             task_queue: Arc::new(ArrayQueue::new(100)),
             waker_cache: BTreeMap::new(),
+            thread: runtime().scheduler.current_id(),
         }
     }
 
@@ -31,12 +36,17 @@ impl Executor {
         self.task_queue.push(task_id).expect("queue full");
     }
 
+    pub fn has_ready_tasks(&self) -> bool {
+        !self.task_queue.is_empty()
+    }
+
     pub fn run_ready_tasks(&mut self) {
         // destructure `self` to avoid borrow checker errors
         let Self {
             tasks,
             task_queue,
             waker_cache,
+            thread,
         } = self;
 
         while let Ok(task_id) = task_queue.pop() {
@@ -44,7 +54,7 @@ impl Executor {
                 Some(task) => task,
                 None => continue, // task no longer exists
             };
-            let waker = waker_cache.entry(task_id).or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+            let waker = waker_cache.entry(task_id).or_insert_with(|| TaskWaker::new(task_id, task_queue.clone(), *thread));
             let mut context = Context::from_waker(waker);
             match task.poll(&mut context) {
                 Poll::Ready(()) => {
@@ -61,18 +71,22 @@ impl Executor {
 struct TaskWaker {
     task_id: TaskId,
     task_queue: Arc<ArrayQueue<TaskId>>,
+    thread: ThreadId,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>, thread: ThreadId) -> Waker {
         Waker::from(Arc::new(TaskWaker {
             task_id,
             task_queue,
+            thread,
         }))
     }
 
     fn wake_task(&self) {
         self.task_queue.push(self.task_id).expect("task_queue full");
+        // The executor thread might have given up the CPU while waiting for work
+        runtime().scheduler.wake(self.thread);
     }
 }
 
@@ -1,4 +1,6 @@
 pub mod executor;
+pub mod mutex;
 pub mod task;
+pub mod timer;
 
 pub use task::*;
@@ -0,0 +1,92 @@
+use alloc::vec::Vec;
+
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use spin::Mutex;
+
+/// Mutex for tasks that can be held across await points.
+pub struct AsyncMutex<T> {
+    locked: AtomicBool,
+    waiters: Mutex<Vec<Waker>>,
+    data: UnsafeCell<T>
+}
+
+pub struct AsyncMutexGuard<'a, T> {
+    mutex: &'a AsyncMutex<T>
+}
+
+pub struct Lock<'a, T> {
+    mutex: &'a AsyncMutex<T>
+}
+
+unsafe impl<T: Send> Send for AsyncMutex<T> {}
+unsafe impl<T: Send> Sync for AsyncMutex<T> {}
+
+impl<T> AsyncMutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: Mutex::new(Vec::new()),
+            data: UnsafeCell::new(data)
+        }
+    }
+
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock {
+            mutex: self
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+}
+
+impl<'a, T> Future for Lock<'a, T> {
+    type Output = AsyncMutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mutex = self.mutex;
+        if !mutex.try_lock() {
+            mutex.waiters.lock().push(cx.waker().clone());
+
+            // Retry as the lock may have been released before the waker was added
+            if !mutex.try_lock() {
+                return Poll::Pending;
+            }
+        }
+
+        Poll::Ready(AsyncMutexGuard {
+            mutex
+        })
+    }
+}
+
+impl<T> Deref for AsyncMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for AsyncMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for AsyncMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+
+        // Wake all waiters, as some of them may no longer be interested
+        let waiters = core::mem::take(&mut *self.mutex.waiters.lock());
+        waiters.into_iter().for_each(Waker::wake);
+    }
+}
@@ -0,0 +1,90 @@
+use alloc::collections::BTreeMap;
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use spin::Mutex;
+
+use crate::arch::Arch;
+use crate::arch::system::System;
+use crate::runtime::runtime;
+
+pub struct Timers {
+    queue: Mutex<BTreeMap<(Duration, u64), Waker>>,
+    next_id: AtomicU64
+}
+
+pub struct Sleep {
+    deadline: Duration,
+    id: u64,
+    registered: bool
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Self {
+            queue: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(0)
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Duration> {
+        Arch::without_interrupts(|| self.queue.lock().keys().next().map(|(deadline, _)| *deadline))
+    }
+
+    /// Wake all tasks with a deadline before `now`, called from the timer interrupt.
+    pub fn expire(&self, now: Duration) {
+        let mut queue = self.queue.lock();
+        while let Some(entry) = queue.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            entry.remove().wake();
+        }
+    }
+
+    fn register(&self, deadline: Duration, id: u64, waker: &Waker) {
+        Arch::without_interrupts(|| self.queue.lock().insert((deadline, id), waker.clone()));
+    }
+
+    fn cancel(&self, deadline: Duration, id: u64) {
+        Arch::without_interrupts(|| self.queue.lock().remove(&(deadline, id)));
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Arch::monotonic_time() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        runtime().timers.register(self.deadline, self.id, cx.waker());
+        self.registered = true;
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if self.registered {
+            runtime().timers.cancel(self.deadline, self.id);
+        }
+    }
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Arch::monotonic_time() + duration)
+}
+
+pub fn sleep_until(deadline: Duration) -> Sleep {
+    Sleep {
+        deadline,
+        id: runtime().timers.next_id.fetch_add(1, Ordering::Relaxed),
+        registered: false
+    }
+}
@@ -0,0 +1,200 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::arch::Arch;
+use crate::arch::system::System;
+
+pub const CLOCK_REALTIME: u64 = 0;
+pub const CLOCK_MONOTONIC: u64 = 1;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64
+}
+
+pub struct Clock {
+    // Realtime in nanoseconds at the moment the monotonic clock started
+    offset: AtomicU64
+}
+
+impl DateTime {
+    pub fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Option<Self> {
+        if year < 1970 || !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) || hour > 23 || minute > 59 || second > 59 {
+            return None;
+        }
+
+        Some(Self {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            nanosecond: 0
+        })
+    }
+
+    pub fn from_unix(time: Duration) -> Self {
+        let secs = time.as_secs();
+        let days = secs / SECONDS_PER_DAY;
+        let rem = secs % SECONDS_PER_DAY;
+
+        // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+        let z = days as i64 + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+            nanosecond: time.subsec_nanos()
+        }
+    }
+
+    pub fn to_unix(&self) -> Duration {
+        // Days from civil, see http://howardhinnant.github.io/date_algorithms.html
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let mp = (self.month as i64 + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = (era * 146097 + doe - 719468) as u64;
+
+        let secs = days * SECONDS_PER_DAY + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64;
+        Duration::new(secs, self.nanosecond)
+    }
+
+    /// Decode a FAT directory entry timestamp, a zero date means the field is unused.
+    pub fn from_fat(date: u16, time: u16) -> Option<Self> {
+        if date == 0 {
+            return None;
+        }
+
+        Self::new(
+            1980 + (date >> 9) as u16,
+            ((date >> 5) & 0xF) as u8,
+            (date & 0x1F) as u8,
+            (time >> 11) as u8,
+            ((time >> 5) & 0x3F) as u8,
+            ((time & 0x1F) * 2) as u8
+        )
+    }
+
+    /// Encode as FAT date and time, clamped to the years FAT can represent.
+    pub fn to_fat(&self) -> (u16, u16) {
+        let year = self.year.clamp(1980, 2107) - 1980;
+        let date = year << 9 | (self.month as u16) << 5 | self.day as u16;
+        let time = (self.hour as u16) << 11 | (self.minute as u16) << 5 | (self.second / 2) as u16;
+        (date, time)
+    }
+}
+
+impl From<&bootloader::Time> for DateTime {
+    fn from(time: &bootloader::Time) -> Self {
+        let local = Self {
+            year: time.year,
+            month: time.month,
+            day: time.day,
+            hour: time.hour,
+            minute: time.minute,
+            second: time.second,
+            nanosecond: time.nanosecond
+        };
+
+        match time.time_zone {
+            Some(offset) => {
+                let utc = local.to_unix().as_secs() as i64 - offset as i64 * 60;
+                Self::from_unix(Duration::new(utc as u64, time.nanosecond))
+            },
+            None => local
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+impl From<Duration> for Timespec {
+    fn from(time: Duration) -> Self {
+        Self {
+            tv_sec: time.as_secs() as i64,
+            tv_nsec: time.subsec_nanos() as i64
+        }
+    }
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            offset: AtomicU64::new(0)
+        }
+    }
+
+    pub fn monotonic(&self) -> Duration {
+        Arch::monotonic_time()
+    }
+
+    pub fn realtime(&self) -> Duration {
+        Duration::from_nanos(self.offset.load(Ordering::Relaxed)) + self.monotonic()
+    }
+
+    pub fn now(&self) -> DateTime {
+        DateTime::from_unix(self.realtime())
+    }
+
+    pub fn set_realtime(&self, time: &DateTime) {
+        let offset = time.to_unix().saturating_sub(self.monotonic());
+        self.offset.store(offset.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, clock_id: u64) -> Option<Duration> {
+        match clock_id {
+            CLOCK_REALTIME => Some(self.realtime()),
+            CLOCK_MONOTONIC => Some(self.monotonic()),
+            _ => None
+        }
+    }
+}
+
+fn is_leap_year(year: u16) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}